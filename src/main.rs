@@ -1,21 +1,49 @@
+use crate::pihole::archive;
+use crate::pihole::cli;
+use crate::pihole::conf::KeyPolicy;
+use crate::pihole::conflict::ConflictPolicy;
 use crate::pihole::dhcp;
 use crate::pihole::dns;
+use crate::pihole::dnsmasq;
+use crate::pihole::explain;
+use crate::pihole::filter::{self, Filters};
+use crate::pihole::ftl;
 use crate::pihole::gravity;
-use clap::Parser;
+use crate::pihole::hosts;
+use crate::pihole::info;
+use crate::pihole::inspect::{self, OutputFormat};
+use crate::pihole::lint;
+use crate::pihole::regexp;
+use crate::pihole::schema;
+use crate::pihole::scope::{GroupOptions, GroupScope};
+use crate::pihole::setupvars;
+use crate::pihole::summary::Summary;
+use crate::pihole::wildcard;
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::Env;
 use flate2::read::GzDecoder;
 use log::{debug, error, info, warn};
 use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use tar::Archive;
 
 mod pihole;
 
 #[derive(Parser, Debug)]
-#[command(author, version)]
+#[command(
+    author,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// teleporter archive file to restore from
-    #[arg(short = 'f', long = "file")]
-    file: String,
+    #[arg(short = 'f', long = "file", required = true)]
+    file: Option<String>,
 
     /// gravity db file location
     #[arg(short, long, default_value = "/etc/pihole/gravity.db")]
@@ -25,54 +53,357 @@ struct Args {
     #[arg(short = 'c', long = "clear", default_value_t = false)]
     flush: bool,
 
-    /// filter which config to restore, specify in comma separated keywords
+    /// filter which config to restore, specify in comma separated keywords, groups of keywords
+    /// or `*` patterns, negated by a leading `!`
     #[arg(long = "filters", default_value = "all")]
     filters: String,
+
+    /// filters to leave out of the ones given with --filters, in the same format
+    #[arg(long = "exclude")]
+    exclude: Option<String>,
+
+    /// don't restore the group assignments of the restored domains, adlists and clients
+    #[arg(long = "no-assignments", default_value_t = false)]
+    no_assignments: bool,
+
+    /// only restore these groups and the entries assigned to them, comma separated group names
+    #[arg(long = "groups")]
+    groups: Option<String>,
+
+    /// assign every restored domain, adlist and client to this group instead of the archive's
+    /// groups, the group is created if it doesn't exist
+    #[arg(long = "assign-group")]
+    assign_group: Option<String>,
+
+    /// number of records to read from the archive and insert at a time
+    #[arg(long = "batch-size", default_value_t = gravity::DEFAULT_BATCH_SIZE)]
+    batch_size: usize,
+
+    /// relax SQLite durability settings while restoring to speed up large imports
+    #[arg(long = "bulk", default_value_t = false)]
+    bulk: bool,
+
+    /// restore into a copy of the gravity db and swap it in once done
+    #[arg(long = "staging", default_value_t = false)]
+    staging: bool,
+
+    /// milliseconds to wait on a gravity db lock held by pihole-FTL before a statement fails
+    #[arg(long = "busy-timeout", default_value_t = gravity::DEFAULT_BUSY_TIMEOUT_MS)]
+    busy_timeout: u64,
+
+    /// times to retry a statement that failed because the gravity db is locked
+    #[arg(long = "lock-retries", default_value_t = gravity::DEFAULT_LOCK_RETRIES)]
+    lock_retries: u32,
+
+    /// what to do when a gravity update (pihole -g) is running
+    #[arg(long = "on-gravity-update", value_enum, default_value_t = GravityUpdatePolicy::Wait)]
+    on_gravity_update: GravityUpdatePolicy,
+
+    /// seconds to wait for a running gravity update to finish
+    #[arg(long = "gravity-wait-timeout", default_value_t = 600)]
+    gravity_wait_timeout: u64,
+
+    /// create the gravity db with the Pi-hole v5 schema if it doesn't exist
+    #[arg(long = "create-db", default_value_t = false)]
+    create_db: bool,
+
+    /// restore an archive taken from a newer gravity schema than the target's, leaving out the
+    /// fields the target doesn't have
+    #[arg(long = "allow-newer-archive", default_value_t = false)]
+    allow_newer_archive: bool,
+
+    /// don't restart the DNS service after restoring, ex: when building a gravity db offline
+    #[arg(long = "no-restart", default_value_t = false)]
+    no_restart: bool,
+
+    /// setupVars.conf keys to take from the archive, comma separated, `*` as wildcard
+    #[arg(long = "setupvars-allow", default_value = setupvars::DEFAULT_ALLOWED_KEYS)]
+    setupvars_allow: String,
+
+    /// setupVars.conf keys to never take from the archive, overrides --setupvars-allow
+    #[arg(long = "setupvars-deny", default_value = setupvars::DEFAULT_DENIED_KEYS)]
+    setupvars_deny: String,
+
+    /// pihole-FTL.conf keys to take from the archive, comma separated, `*` as wildcard
+    #[arg(long = "ftlconf-allow", default_value = ftl::DEFAULT_ALLOWED_KEYS)]
+    ftlconf_allow: String,
+
+    /// pihole-FTL.conf keys to never take from the archive, overrides --ftlconf-allow
+    #[arg(long = "ftlconf-deny", default_value = ftl::DEFAULT_DENIED_KEYS)]
+    ftlconf_deny: String,
+
+    /// additional dnsmasq.d files to restore, comma separated, `*` as wildcard
+    #[arg(long = "dnsmasq-files", default_value = dnsmasq::DEFAULT_FILE_PATTERNS)]
+    dnsmasq_files: String,
+
+    /// what to do with static DHCP leases sharing a MAC, IP or host name with an existing one
+    #[arg(long = "dhcp-conflicts", value_enum, default_value_t = ConflictPolicy::Skip)]
+    dhcp_conflicts: ConflictPolicy,
+
+    /// what to do with Local DNS and CNAME records for domains that resolve differently already
+    #[arg(long = "dns-conflicts", value_enum, default_value_t = ConflictPolicy::Skip)]
+    dns_conflicts: ConflictPolicy,
+
+    /// reject CNAME records whose target doesn't resolve locally instead of only reporting them
+    #[arg(long = "reject-dangling-cnames", default_value_t = false)]
+    reject_dangling_cnames: bool,
+
+    /// skip static DHCP leases whose address or host name is actively leased to another client
+    #[arg(long = "skip-leased", default_value_t = false)]
+    skip_leased: bool,
+
+    /// how to restore the entries of the archived hosts file
+    #[arg(long = "hosts-mode", value_enum, default_value_t = HostsMode::Merge)]
+    hosts_mode: HostsMode,
+}
+
+// commands that read an archive without restoring it
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// report contradictions and redundancies among the lists in an archive
+    Lint {
+        /// teleporter archive file to check
+        #[arg(short = 'f', long = "file")]
+        file: String,
+    },
+    /// explain whether a domain would be blocked for a client, and by which entry
+    Explain {
+        /// teleporter archive file to take the lists from
+        #[arg(
+            short = 'f',
+            long = "file",
+            required_unless_present = "database",
+            conflicts_with = "database"
+        )]
+        file: Option<String>,
+
+        /// gravity db file to take the lists from instead of an archive
+        #[arg(short, long)]
+        database: Option<String>,
+
+        /// domain to look up
+        #[arg(long = "domain")]
+        domain: String,
+
+        /// IP address, MAC address, host name or interface of the client sending the query
+        #[arg(long = "client")]
+        client: String,
+
+        /// type of the query, ex: AAAA
+        #[arg(long = "type", default_value = "A")]
+        query_type: String,
+    },
+    /// list the entries of an archive with the number of records they hold
+    Info {
+        /// teleporter archive file to list
+        #[arg(short = 'f', long = "file")]
+        file: String,
+    },
+    /// run an SQL query against the sections of an archive loaded into an in-memory database
+    Inspect {
+        /// teleporter archive file to load
+        #[arg(short = 'f', long = "file")]
+        file: String,
+
+        /// query to run, ex: "SELECT domain FROM domainlist WHERE type = 1"
+        #[arg(long = "sql")]
+        sql: String,
+
+        /// how to print the results
+        #[arg(long = "format", value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HostsMode {
+    /// add the entries missing in /etc/hosts
+    Merge,
+    /// add the entries as Local DNS records in custom.list instead
+    Localdns,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GravityUpdatePolicy {
+    /// wait for the gravity update to finish before restoring
+    Wait,
+    /// exit without restoring anything
+    Refuse,
+    /// restore regardless, relying on the busy timeout and retries
+    Ignore,
 }
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = Args::parse();
 
-    let tar_gz_file = args.file;
+    if let Some(command) = args.command {
+        std::process::exit(run_command(command));
+    }
+
+    let tar_gz_file = args.file.expect("the archive file is required to restore");
     let sqlite_db_file = args.database;
     let flush_tables = args.flush;
 
-    let all_filters = vec![
-        "blacklist".to_string(),
-        "blacklistregex".to_string(),
-        "whitelist".to_string(),
-        "whitelistregex".to_string(),
-        "adlist".to_string(),
-        "auditlog".to_string(),
-        "group".to_string(),
-        "client".to_string(),
-        "staticdhcp".to_string(),
-        "localdns".to_string(),
-        "localcname".to_string(),
-    ];
-
-    let filters;
-    if args.filters == "all" {
-        filters = all_filters;
-    } else {
-        filters = args
-            .filters
-            .split(',')
-            .map(String::from)
-            .map(|f| f.to_lowercase())
-            .collect();
-
-        // early fail on invalid filters
-        let check_filters = filters.clone();
-        for f in check_filters {
-            if !all_filters.contains(&f) {
-                error!("invalid filter found: {}", f);
+    // early fail on invalid filters
+    let filters = match Filters::parse(
+        &args.filters,
+        args.exclude.as_deref().unwrap_or_default(),
+        !args.no_assignments,
+    ) {
+        Ok(filters) => filters,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("restoring {}", filters.keywords().join(", "));
+
+    // the group sections come last in the archive, so the scope has to be known up front
+    let scope = match &args.groups {
+        Some(groups) => match GroupScope::from_archive(&tar_gz_file, &wildcard::split_list(groups))
+        {
+            Ok(scope) => Some(scope),
+            Err(e) => {
+                error!("cannot restore groups {}: {}", groups, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // gravity updates rebuild the database while holding an exclusive lock
+    if args.on_gravity_update != GravityUpdatePolicy::Ignore && cli::is_gravity_updating() {
+        match args.on_gravity_update {
+            GravityUpdatePolicy::Refuse => {
+                error!("a gravity update is running, not restoring until it finishes");
+                std::process::exit(1);
+            }
+            _ => {
+                info!("a gravity update is running, waiting for it to finish");
+                if !cli::wait_for_gravity_update(Duration::from_secs(args.gravity_wait_timeout)) {
+                    error!(
+                        "gravity update did not finish within {}s",
+                        args.gravity_wait_timeout
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if !Path::new(&sqlite_db_file).exists() {
+        if args.create_db {
+            if let Err(e) = schema::create_gravity_db(&sqlite_db_file) {
+                error!("error while creating gravity db {}: {}", sqlite_db_file, e);
+                std::process::exit(1);
+            }
+        } else {
+            error!(
+                "gravity db {} does not exist, use --create-db to create a new one",
+                sqlite_db_file
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // refuse early rather than failing every insert on an unsupported schema
+    match gravity::target_schema_version(&sqlite_db_file) {
+        Ok(version) => {
+            if let Some(v) = version {
+                info!("target gravity schema version: {}", v);
+            }
+            if let Err(reason) = schema::check_target_version(version) {
+                error!("cannot restore into {}: {}", sqlite_db_file, reason);
                 std::process::exit(1);
             }
+            // with --allow-newer-archive the dropped fields are reported by each section
+            match version {
+                Some(target_version) if !args.allow_newer_archive => {
+                    check_archive_version(&tar_gz_file, target_version)
+                }
+                _ => {}
+            }
+        }
+        Err(e) => {
+            error!(
+                "error while reading the schema version of {}: {}",
+                sqlite_db_file, e
+            );
+            std::process::exit(1);
         }
     }
 
+    let mut db = gravity::DbConfig {
+        file: sqlite_db_file.clone(),
+        batch_size: args.batch_size,
+        bulk: args.bulk,
+        busy_timeout: Duration::from_millis(args.busy_timeout),
+        lock_retries: args.lock_retries,
+    };
+
+    // when staging, everything is restored into a copy which replaces the database at the end
+    if args.staging {
+        match gravity::create_staging_copy(&db) {
+            Ok(staging_file) => {
+                info!("restoring into staging copy {}", staging_file);
+                db.file = staging_file;
+            }
+            Err(e) => {
+                error!(
+                    "error while creating a staging copy of {}: {}",
+                    sqlite_db_file, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut group_options = GroupOptions {
+        scope,
+        target_group_id: None,
+        assignments: filters.assignments(),
+    };
+    if let Some(name) = &args.assign_group {
+        match gravity::ensure_group(&db, name) {
+            Ok(id) => group_options.target_group_id = Some(id),
+            Err(e) => {
+                error!("error while creating group {}: {}", name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut summary = Summary::new();
+
+    // with a staging copy, a partly restored gravity db is never swapped in
+    let mut gravity_failed = false;
+
+    // pihole-FTL reads its own config only on startup, a reload of the DNS service won't do
+    let mut ftl_restart_required = false;
+
+    // dnsmasq doesn't read setupVars.conf, these keys only apply once 01-pihole.conf is
+    // rewritten from it
+    let mut pending_dns_settings: Vec<String> = Vec::new();
+
+    // a malformed dnsmasq.d file makes pihole-FTL refuse to start, so the restored files are
+    // tested before restarting and rolled back to this snapshot if they broke the configuration
+    let dnsmasq_snapshot =
+        if filters.contains_any(&["staticdhcp", "localcname", "localdns", "hosts", "dnsmasq"]) {
+            match dnsmasq::Snapshot::take() {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    error!(
+                        "error while taking a snapshot of the dnsmasq configuration: {}",
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+
     info!("start importing...");
     let file = match File::open(&tar_gz_file) {
         Ok(f) => f,
@@ -93,19 +424,23 @@ fn main() {
 
         match file_name {
             "blacklist.exact.json" => {
-                if filters.contains(&String::from("blacklist")) {
+                if filters.contains("blacklist") {
+                    let started = Instant::now();
                     let result = gravity::restore_domainlist(
-                        &sqlite_db_file,
+                        &db,
                         gravity::DomainType::Blacklist,
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("blacklist (exact)", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed blacklist (exact) ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while processing blacklist domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -116,19 +451,23 @@ fn main() {
                 }
             }
             "blacklist.regex.json" => {
-                if filters.contains(&String::from("blacklistregex")) {
+                if filters.contains("blacklistregex") {
+                    let started = Instant::now();
                     let result = gravity::restore_domainlist(
-                        &sqlite_db_file,
+                        &db,
                         gravity::DomainType::BlacklistRegex,
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("blacklist (regex)", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed blacklist (regex) ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading regex_blacklist domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -139,19 +478,23 @@ fn main() {
                 }
             }
             "whitelist.exact.json" => {
-                if filters.contains(&String::from("whitelist")) {
+                if filters.contains("whitelist") {
+                    let started = Instant::now();
                     let result = gravity::restore_domainlist(
-                        &sqlite_db_file,
+                        &db,
                         gravity::DomainType::Whitelist,
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("whitelist (exact)", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed whitelist (exact) ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading whitelist domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -162,19 +505,23 @@ fn main() {
                 }
             }
             "whitelist.regex.json" => {
-                if filters.contains(&String::from("whitelistregex")) {
+                if filters.contains("whitelistregex") {
+                    let started = Instant::now();
                     let result = gravity::restore_domainlist(
-                        &sqlite_db_file,
+                        &db,
                         gravity::DomainType::WhitelistRegex,
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("whitelist (regex)", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed whitelist (regex) ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading regex_whitelist domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -185,15 +532,23 @@ fn main() {
                 }
             }
             "adlist.json" => {
-                if filters.contains(&String::from("adlist")) {
-                    let result =
-                        gravity::load_table(&sqlite_db_file, "adlist", &mut tar_file, flush_tables);
+                if filters.contains("adlist") {
+                    let started = Instant::now();
+                    let result = gravity::load_table(
+                        &db,
+                        "adlist",
+                        &mut tar_file,
+                        flush_tables,
+                        &group_options,
+                    );
+                    summary.add("adlist", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed adlist ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading adlist domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -204,19 +559,23 @@ fn main() {
                 }
             }
             "domain_audit.json" => {
-                if filters.contains(&String::from("auditlog")) {
+                if filters.contains("auditlog") {
+                    let started = Instant::now();
                     let result = gravity::load_table(
-                        &sqlite_db_file,
+                        &db,
                         "domain_audit",
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("domain_audit", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed domain_audit ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading audit domains: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -227,15 +586,28 @@ fn main() {
                 }
             }
             "group.json" => {
-                if filters.contains(&String::from("group")) {
-                    let result =
-                        gravity::load_table(&sqlite_db_file, "group", &mut tar_file, flush_tables);
+                if group_options.target_group_id.is_some() {
+                    info!(
+                        "not processing {} because entries are assigned to the group given with --assign-group",
+                        file_name
+                    );
+                } else if filters.contains("group") {
+                    let started = Instant::now();
+                    let result = gravity::load_table(
+                        &db,
+                        "group",
+                        &mut tar_file,
+                        flush_tables,
+                        &group_options,
+                    );
+                    summary.add("group", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed group ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading groups: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -246,15 +618,23 @@ fn main() {
                 }
             }
             "client.json" => {
-                if filters.contains(&String::from("client")) {
-                    let result =
-                        gravity::load_table(&sqlite_db_file, "client", &mut tar_file, flush_tables);
+                if filters.contains("client") {
+                    let started = Instant::now();
+                    let result = gravity::load_table(
+                        &db,
+                        "client",
+                        &mut tar_file,
+                        flush_tables,
+                        &group_options,
+                    );
+                    summary.add("client", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed client ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading clients: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -265,19 +645,33 @@ fn main() {
                 }
             }
             "client_by_group.json" => {
-                if filters.contains(&String::from("client")) {
+                if group_options.target_group_id.is_some() {
+                    info!(
+                        "not processing {} because entries are assigned to the group given with --assign-group",
+                        file_name
+                    );
+                } else if !filters.assignments() {
+                    info!(
+                        "not processing {} because --no-assignments was specified",
+                        file_name
+                    );
+                } else if filters.contains("client") {
+                    let started = Instant::now();
                     let result = gravity::load_table(
-                        &sqlite_db_file,
+                        &db,
                         "client_by_group",
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("client_by_group", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed client group assignments ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading client_by_group: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -288,17 +682,26 @@ fn main() {
                 }
             }
             "domainlist_by_group.json" => {
-                if filters.contains(&String::from("blacklist"))
-                    || filters.contains(&String::from("blacklistregex"))
-                    || filters.contains(&String::from("whitelist"))
-                    || filters.contains(&String::from("whitelistregex"))
-                {
+                if group_options.target_group_id.is_some() {
+                    info!(
+                        "not processing {} because entries are assigned to the group given with --assign-group",
+                        file_name
+                    );
+                } else if !filters.assignments() {
+                    info!(
+                        "not processing {} because --no-assignments was specified",
+                        file_name
+                    );
+                } else if filters.contains_any(&filter::DOMAINLIST_KEYWORDS) {
+                    let started = Instant::now();
                     let result = gravity::load_table(
-                        &sqlite_db_file,
+                        &db,
                         "domainlist_by_group",
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("domainlist_by_group", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!(
@@ -308,6 +711,7 @@ fn main() {
                         }
                         Err(e) => {
                             warn!("error while loading domainlist_by_group: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -318,19 +722,33 @@ fn main() {
                 }
             }
             "adlist_by_group.json" => {
-                if filters.contains(&String::from("adlist")) {
+                if group_options.target_group_id.is_some() {
+                    info!(
+                        "not processing {} because entries are assigned to the group given with --assign-group",
+                        file_name
+                    );
+                } else if !filters.assignments() {
+                    info!(
+                        "not processing {} because --no-assignments was specified",
+                        file_name
+                    );
+                } else if filters.contains("adlist") {
+                    let started = Instant::now();
                     let result = gravity::load_table(
-                        &sqlite_db_file,
+                        &db,
                         "adlist_by_group",
                         &mut tar_file,
                         flush_tables,
+                        &group_options,
                     );
+                    summary.add("adlist_by_group", &result, started.elapsed());
                     match result {
                         Ok(count) => {
                             info!("processed adlist group assginments ({} entries)", count);
                         }
                         Err(e) => {
                             warn!("error while loading adlist_by_group: {}", e);
+                            gravity_failed = true;
                         }
                     }
                 } else {
@@ -341,8 +759,16 @@ fn main() {
                 }
             }
             "dnsmasq.d/04-pihole-static-dhcp.conf" => {
-                if filters.contains(&String::from("staticdhcp")) {
-                    match dhcp::process_static_dhcp(&mut tar_file, flush_tables) {
+                if filters.contains("staticdhcp") {
+                    let started = Instant::now();
+                    let result = dhcp::process_static_dhcp(
+                        &mut tar_file,
+                        flush_tables,
+                        args.dhcp_conflicts,
+                        args.skip_leased,
+                    );
+                    summary.add("static dhcp", &result, started.elapsed());
+                    match result {
                         Err(e) => warn!("error while processing the static dhcp leases: {}", e),
                         Ok(count) => info!("processed static dhcp leases ({} entries)", count),
                     }
@@ -354,8 +780,15 @@ fn main() {
                 }
             }
             "custom.list" => {
-                if filters.contains(&String::from("localdns")) {
-                    match dns::process_local_dns_entries(&mut tar_file, flush_tables) {
+                if filters.contains("localdns") {
+                    let started = Instant::now();
+                    let result = dns::process_local_dns_entries(
+                        &mut tar_file,
+                        flush_tables,
+                        args.dns_conflicts,
+                    );
+                    summary.add("local dns", &result, started.elapsed());
+                    match result {
                         Err(e) => warn!("error while processing custom.list restore: {}", e),
                         Ok(count) => info!("processed local DNS records ({} entries)", count),
                     }
@@ -367,8 +800,16 @@ fn main() {
                 }
             }
             "dnsmasq.d/05-pihole-custom-cname.conf" => {
-                if filters.contains(&String::from("localcname")) {
-                    match dns::process_local_cname_entries(&mut tar_file, flush_tables) {
+                if filters.contains("localcname") {
+                    let started = Instant::now();
+                    let result = dns::process_local_cname_entries(
+                        &mut tar_file,
+                        flush_tables,
+                        args.dns_conflicts,
+                        args.reject_dangling_cnames,
+                    );
+                    summary.add("local cname", &result, started.elapsed());
+                    match result {
                         Err(e) => warn!("error while processing custom cname restore: {}", e),
                         Ok(count) => info!("processed local CNAME records ({} entries)", count),
                     }
@@ -380,23 +821,312 @@ fn main() {
                 }
             }
 
+            "setupVars.conf" => {
+                if filters.contains("setupvars") {
+                    let policy = KeyPolicy::from_lists(&args.setupvars_allow, &args.setupvars_deny);
+                    let started = Instant::now();
+                    match setupvars::process_setupvars(&mut tar_file, &policy) {
+                        Err(e) => {
+                            warn!("error while processing setupVars.conf restore: {}", e);
+                            summary.add("setupvars", &Err(e), started.elapsed());
+                        }
+                        Ok(keys) => {
+                            info!("processed setupVars.conf ({} keys changed)", keys.len());
+                            summary.add("setupvars", &Ok(keys.len() as i32), started.elapsed());
+                            pending_dns_settings = setupvars::dns_settings(&keys);
+                        }
+                    }
+                } else {
+                    info!(
+                        "not processing {} because enforced filter does not specify setupvars",
+                        file_name
+                    );
+                }
+            }
+            archive::HOSTS | archive::HOSTS_ALIAS => {
+                if filters.contains("hosts") {
+                    let started = Instant::now();
+                    let result = match args.hosts_mode {
+                        HostsMode::Merge => hosts::merge_hosts(&mut tar_file),
+                        HostsMode::Localdns => hosts::convert_to_local_dns(&mut tar_file),
+                    };
+                    summary.add("hosts", &result, started.elapsed());
+                    match result {
+                        Err(e) => warn!("error while processing hosts restore: {}", e),
+                        Ok(count) => info!("processed hosts ({} entries)", count),
+                    }
+                } else {
+                    info!(
+                        "not processing {} because enforced filter does not specify hosts",
+                        file_name
+                    );
+                }
+            }
+            "pihole-FTL.conf" => {
+                if filters.contains("ftlconf") {
+                    let policy = KeyPolicy::from_lists(&args.ftlconf_allow, &args.ftlconf_deny);
+                    let started = Instant::now();
+                    let result = ftl::process_ftl_conf(&mut tar_file, &policy);
+                    summary.add("ftlconf", &result, started.elapsed());
+                    match result {
+                        Err(e) => warn!("error while processing pihole-FTL.conf restore: {}", e),
+                        Ok(count) => {
+                            info!("processed pihole-FTL.conf ({} keys changed)", count);
+                            if count > 0 {
+                                ftl_restart_required = true;
+                                summary.note("pihole-FTL.conf changed, pihole-FTL has to be restarted for it to take effect");
+                            }
+                        }
+                    }
+                } else {
+                    info!(
+                        "not processing {} because enforced filter does not specify ftlconf",
+                        file_name
+                    );
+                }
+            }
+            name if name.starts_with("dnsmasq.d/") && !name.ends_with('/') => {
+                if filters.contains("dnsmasq") {
+                    // the name borrows from the entry, which is read from below
+                    let name = name.to_string();
+                    let patterns = wildcard::split_list(&args.dnsmasq_files);
+                    let started = Instant::now();
+                    let result = dnsmasq::restore_extra_file(&mut tar_file, &name, &patterns);
+                    summary.add(&name, &result, started.elapsed());
+                    match result {
+                        Err(e) => warn!("error while processing {} restore: {}", name, e),
+                        Ok(count) => info!("processed {} ({} lines changed)", name, count),
+                    }
+                } else {
+                    info!(
+                        "not processing {} because enforced filter does not specify dnsmasq",
+                        file_name
+                    );
+                }
+            }
+
             _ => debug!("to be supported: {}", file_name),
         }
     }
 
-    // restart DNS to be sure
-    match pihole::cli::restart_dns() {
-        Ok(_) => {
-            info!("restarted dns service");
+    let mut restore_failed = false;
+    if args.staging && gravity_failed {
+        error!(
+            "not replacing {} since some of its sections failed to restore",
+            sqlite_db_file
+        );
+        summary.note(&format!(
+            "{} was left as it was since some of its sections failed to restore",
+            sqlite_db_file
+        ));
+        if let Err(e) = gravity::discard_staging_copy(&db.file) {
+            warn!("error while removing the staging copy {}: {}", db.file, e);
         }
-        Err(e) => {
-            error!(
-                "error while restarting dns service after processing archive: {}",
-                e
+        restore_failed = true;
+    } else if args.staging {
+        match gravity::swap_in_staging_copy(&db.file, &sqlite_db_file) {
+            Ok(_) => info!("replaced {} with the restored copy", sqlite_db_file),
+            Err(e) => {
+                error!("error while swapping in the restored database: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // a configuration that fails the config test without being rolled back would keep
+    // pihole-FTL from starting again
+    let mut dns_config_broken = false;
+    if let Some(snapshot) = &dnsmasq_snapshot {
+        if let Err(e) = dnsmasq::validate_changes(snapshot) {
+            error!("{}", e);
+            summary.note(&e.to_string());
+            restore_failed = true;
+            dns_config_broken = snapshot.failed_before();
+        }
+    }
+
+    let dns_restarted = !pending_dns_settings.is_empty()
+        && apply_dns_settings(
+            &pending_dns_settings,
+            !args.no_restart && !restore_failed,
+            &mut summary,
+        );
+
+    summary.print();
+
+    if args.no_restart {
+        info!("not restarting dns service since --no-restart was specified");
+        if ftl_restart_required {
+            warn!(
+                "restart pihole-FTL with `pihole restartdns` to apply the restored pihole-FTL.conf"
             );
-            std::process::exit(2);
+        }
+        info!("done importing");
+        if restore_failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if dns_config_broken {
+        warn!("not restarting dns service since the dnsmasq configuration fails the config test");
+        std::process::exit(1);
+    }
+
+    // restart DNS to be sure, this is a full restart of pihole-FTL which also applies the
+    // restored pihole-FTL.conf, applying the DNS settings already restarted it
+    if !dns_restarted {
+        match cli::restart_dns() {
+            Ok(_) => {
+                info!("restarted dns service");
+            }
+            Err(e) => {
+                error!(
+                    "error while restarting dns service after processing archive: {}",
+                    e
+                );
+                std::process::exit(2);
+            }
         }
     }
 
     info!("done importing");
+    if restore_failed {
+        std::process::exit(1);
+    }
+}
+
+/// Has Pihole rewrite dnsmasq.d/01-pihole.conf from the restored setupVars.conf DNS settings,
+/// which restarts the DNS service, or notes the keys that are still waiting to be applied.
+/// Returns whether the settings were applied.
+fn apply_dns_settings(keys: &[String], apply: bool, summary: &mut Summary) -> bool {
+    let mode = match setupvars::listening_mode() {
+        Ok(mode) => mode,
+        Err(e) => {
+            warn!("error while reading setupVars.conf: {}", e);
+            String::from("single")
+        }
+    };
+
+    if apply {
+        match cli::apply_dns_settings(&mode) {
+            Ok(output) if output.status.success() => {
+                info!("applied setupVars.conf DNS settings: {}", keys.join(", "));
+                return true;
+            }
+            Ok(output) => warn!(
+                "error while applying setupVars.conf DNS settings: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("error while applying setupVars.conf DNS settings: {}", e),
+        }
+    }
+    summary.note(&format!(
+        "restored setupVars.conf keys {} only take effect once Pihole rewrites dnsmasq.d/01-pihole.conf, ex: with `pihole -a -i {}`",
+        keys.join(", "),
+        mode
+    ));
+    false
+}
+
+/// Refuses to restore an archive taken from a newer gravity schema than the target's before
+/// anything is written, as its fields would be dropped
+fn check_archive_version(archive_file: &str, target_version: i32) {
+    let versions = match archive::implied_schema_versions(archive_file) {
+        Ok(versions) => versions,
+        Err(e) => {
+            error!("error while reading {}: {}", archive_file, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some((table, version)) = versions
+        .into_iter()
+        .find(|(_, version)| *version > target_version)
+    {
+        error!(
+            "{} section was taken from gravity schema version {} or newer, the target has version {}, use --allow-newer-archive to restore it anyway",
+            table, version, target_version
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Runs the given command, returning the exit code
+fn run_command(command: Command) -> i32 {
+    match command {
+        Command::Lint { file } => match lint::lint_archive(&file) {
+            Ok(0) => {
+                info!("no issues found in {}", file);
+                0
+            }
+            Ok(count) => {
+                warn!("{} issues found in {}", count, file);
+                1
+            }
+            Err(e) => {
+                error!("error while linting {}: {}", file, e);
+                2
+            }
+        },
+        Command::Explain {
+            file,
+            database,
+            domain,
+            client,
+            query_type,
+        } => {
+            if !regexp::is_query_type(&query_type) {
+                error!("unknown query type: {}", query_type);
+                return 2;
+            }
+
+            // the archive doesn't have the domains of the adlists, the gravity db does
+            let source = match (file, database) {
+                (Some(file), _) => archive::load_into_memory(&file).map(|conn| (conn, false)),
+                (None, Some(database)) => {
+                    gravity::open_read_only(&database).map(|conn| (conn, true))
+                }
+                (None, None) => Err(Box::<dyn std::error::Error>::from(
+                    "either an archive or a gravity db is required",
+                )),
+            };
+            let result = source.and_then(|(conn, has_gravity)| {
+                explain::explain(
+                    &conn,
+                    &domain,
+                    &client,
+                    &query_type.to_uppercase(),
+                    has_gravity,
+                )
+            });
+            match result {
+                Ok(_) => 0,
+                Err(e) => {
+                    error!("error while explaining {}: {}", domain, e);
+                    2
+                }
+            }
+        }
+        Command::Info { file } => match info::print_info(&file) {
+            Ok(_) => 0,
+            Err(e) => {
+                error!("error while reading {}: {}", file, e);
+                2
+            }
+        },
+        Command::Inspect { file, sql, format } => {
+            let result = archive::load_into_memory(&file)
+                .and_then(|conn| inspect::run_query(&conn, &sql, format));
+            match result {
+                Ok(count) => {
+                    debug!("query returned {} rows", count);
+                    0
+                }
+                Err(e) => {
+                    error!("error while inspecting {}: {}", file, e);
+                    2
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,238 @@
+use crate::pihole::dhcp::DhcpHost;
+use crate::pihole::dns::CnameRecord;
+use crate::pihole::dnsmasq;
+use crate::pihole::gravity::{self, DbConfig, DomainType};
+use crate::pihole::hosts::{HostsFile, HostsLine};
+use crate::pihole::schema;
+use crate::pihole::scope::GroupOptions;
+use flate2::read::GzDecoder;
+use log::{debug, warn, LevelFilter};
+use rusqlite::{params, Connection};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+use tar::Archive;
+
+/// JSON sections of a Teleporter archive restored into the domainlist table, by their type
+pub const DOMAINLIST_SECTIONS: [(&str, DomainType); 4] = [
+    ("blacklist.exact.json", DomainType::Blacklist),
+    ("blacklist.regex.json", DomainType::BlacklistRegex),
+    ("whitelist.exact.json", DomainType::Whitelist),
+    ("whitelist.regex.json", DomainType::WhitelistRegex),
+];
+
+/// JSON sections of a Teleporter archive restored into the gravity table they are named after
+pub const TABLE_SECTIONS: [(&str, &str); 7] = [
+    ("adlist.json", "adlist"),
+    ("domain_audit.json", "domain_audit"),
+    ("group.json", "group"),
+    ("client.json", "client"),
+    ("client_by_group.json", "client_by_group"),
+    ("domainlist_by_group.json", "domainlist_by_group"),
+    ("adlist_by_group.json", "adlist_by_group"),
+];
+
+pub const CUSTOM_LIST: &str = "custom.list";
+pub const CNAME_CONF: &str = "dnsmasq.d/05-pihole-custom-cname.conf";
+pub const STATIC_DHCP_CONF: &str = "dnsmasq.d/04-pihole-static-dhcp.conf";
+/// the Teleporter adds /etc/hosts under `etc/`, bare `hosts` is accepted from hand-made archives
+pub const HOSTS: &str = "etc/hosts";
+pub const HOSTS_ALIAS: &str = "hosts";
+
+/// Config files of a Teleporter archive a restore knows about, besides additional files in
+/// dnsmasq.d
+const CONFIG_SECTIONS: [&str; 7] = [
+    CUSTOM_LIST,
+    CNAME_CONF,
+    STATIC_DHCP_CONF,
+    "setupVars.conf",
+    HOSTS,
+    HOSTS_ALIAS,
+    "pihole-FTL.conf",
+];
+
+/// Tables the records of the config files are loaded into, one row per name, CNAME or
+/// `dhcp-host` option along with the line it is on
+const CONFIG_TABLES: &str = "
+CREATE TABLE custom_list (line INTEGER, ip TEXT, name TEXT, comment TEXT);
+CREATE TABLE custom_cname (line INTEGER, name TEXT, target TEXT, ttl INTEGER);
+CREATE TABLE static_dhcp (line INTEGER, macs TEXT, client_id TEXT, tags TEXT, ipv4 TEXT,
+    ipv6 TEXT, hostname TEXT, lease_time TEXT, ignore INTEGER);
+";
+
+/// Checks whether a restore knows what to do with the given archive entry, when it is asked
+/// for with the filters
+pub fn is_supported(name: &str) -> bool {
+    DOMAINLIST_SECTIONS
+        .iter()
+        .any(|(section, _)| *section == name)
+        || TABLE_SECTIONS.iter().any(|(section, _)| *section == name)
+        || CONFIG_SECTIONS.contains(&name)
+        || dnsmasq::is_extra_file(name)
+}
+
+/// Reads the fields of the table sections of the given archive, returning the oldest gravity
+/// schema version each section could have been taken from, for the sections with version
+/// specific fields
+pub fn implied_schema_versions(
+    archive_file: &str,
+) -> Result<Vec<(&'static str, i32)>, Box<dyn Error>> {
+    let mut versions: Vec<(&'static str, i32)> = Vec::new();
+    let mut archive = open(archive_file)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let table = match TABLE_SECTIONS.iter().find(|(section, _)| *section == name) {
+            Some((_, table)) if schema::has_versioned_columns(table) => *table,
+            _ => continue,
+        };
+
+        let mut fields: BTreeSet<String> = BTreeSet::new();
+        gravity::stream_records(
+            &mut entry,
+            gravity::DEFAULT_BATCH_SIZE,
+            |batch: Vec<Map<String, Value>>| {
+                for record in &batch {
+                    fields.extend(record.keys().cloned());
+                }
+                Ok(batch.len() as i32)
+            },
+        )
+        .map_err(|e| format!("failed to read {}: {}", name, e))?;
+        if let Some(version) = schema::implied_version(table, &fields) {
+            versions.push((table, version));
+        }
+    }
+    Ok(versions)
+}
+
+/// Opens the given Teleporter archive for reading its entries
+pub fn open(archive_file: &str) -> Result<Archive<GzDecoder<File>>, Box<dyn Error>> {
+    let file =
+        File::open(archive_file).map_err(|e| format!("failed to open {}: {}", archive_file, e))?;
+    Ok(Archive::new(GzDecoder::new(file)))
+}
+
+/// Loads the JSON sections of the given archive into an in-memory gravity database, going
+/// through the same normalisation and validation as a restore, without touching this setup.
+/// The records of custom.list, the CNAME and the static DHCP configs are loaded into the
+/// `custom_list`, `custom_cname` and `static_dhcp` tables.
+pub fn load_into_memory(archive_file: &str) -> Result<Connection, Box<dyn Error>> {
+    let conn = schema::create_in_memory_db()?;
+    conn.execute_batch(CONFIG_TABLES)?;
+    let db = DbConfig {
+        file: String::from(":memory:"),
+        batch_size: gravity::DEFAULT_BATCH_SIZE,
+        bulk: false,
+        busy_timeout: Duration::from_millis(gravity::DEFAULT_BUSY_TIMEOUT_MS),
+        lock_retries: 0,
+    };
+
+    // the progress of each section is noise when only reading the archive, warnings about
+    // entries that would not be restored are kept
+    let max_level = log::max_level();
+    log::set_max_level(max_level.min(LevelFilter::Warn));
+    let result = load_sections(&conn, &db, archive_file);
+    log::set_max_level(max_level);
+
+    result.map(|_| conn)
+}
+
+fn load_sections(
+    conn: &Connection,
+    db: &DbConfig,
+    archive_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    // every section is loaded as it is, with the archive's own group assignments
+    let groups = GroupOptions::default();
+    let mut archive = open(archive_file)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        let result = if let Some((_, domain_type)) = DOMAINLIST_SECTIONS
+            .iter()
+            .find(|(section, _)| *section == name)
+        {
+            gravity::restore_domainlist_into(conn, db, *domain_type, &mut entry, false, &groups)
+        } else if let Some((_, table)) = TABLE_SECTIONS.iter().find(|(section, _)| *section == name)
+        {
+            gravity::load_table_into(conn, db, table, &mut entry, false, &groups)
+        } else if name == CUSTOM_LIST || name == CNAME_CONF || name == STATIC_DHCP_CONF {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            load_config_lines(conn, &name, &contents)
+        } else {
+            continue;
+        };
+
+        match result {
+            Ok(count) => debug!("loaded {} ({} entries)", name, count),
+            Err(e) => warn!("error while loading {}: {}", name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Loads the records of the given config file into its table, leaving out the lines that
+/// could not be parsed
+fn load_config_lines(conn: &Connection, name: &str, contents: &str) -> Result<i32, Box<dyn Error>> {
+    let mut count = 0;
+    if name == CUSTOM_LIST {
+        let (hosts_file, _) = HostsFile::parse(contents);
+        let mut stmt = conn.prepare("INSERT INTO custom_list VALUES (?, ?, ?, ?)")?;
+        for (index, line) in hosts_file.lines.iter().enumerate() {
+            if let HostsLine::Entry { entry, comment, .. } = line {
+                for host in &entry.names {
+                    stmt.execute(params![index + 1, entry.ip.to_string(), host, comment])?;
+                    count += 1;
+                }
+            }
+        }
+        return Ok(count);
+    }
+
+    for (index, line) in contents.lines().enumerate() {
+        if name == CNAME_CONF {
+            let record = match CnameRecord::parse(line) {
+                Some(record) => record,
+                None => continue,
+            };
+            for host in &record.names {
+                conn.execute(
+                    "INSERT INTO custom_cname VALUES (?, ?, ?, ?)",
+                    params![index + 1, host, record.target, record.ttl],
+                )?;
+                count += 1;
+            }
+        } else if line.trim().starts_with("dhcp-host=") {
+            let host = match DhcpHost::parse(line) {
+                Ok(host) => host,
+                Err(_) => continue,
+            };
+            conn.execute(
+                "INSERT INTO static_dhcp VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    index + 1,
+                    join_non_empty(&host.macs),
+                    host.client_id,
+                    join_non_empty(&host.set_tags),
+                    host.ipv4.map(|ip| ip.to_string()),
+                    host.ipv6.map(|ip| ip.to_string()),
+                    host.hostname,
+                    host.lease_time,
+                    host.ignore
+                ],
+            )?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn join_non_empty(values: &[String]) -> Option<String> {
+    (!values.is_empty()).then(|| values.join(","))
+}
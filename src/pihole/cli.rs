@@ -1,4 +1,13 @@
+use log::{debug, info};
+use std::fs;
 use std::process::{Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const GRAVITY_UPDATE_POLL_INTERVAL_SECS: u64 = 5;
+
+/// interpreters the pihole command and the gravity script can be run with
+const SHELLS: [&str; 3] = ["bash", "sh", "dash"];
 
 /// Execute a pihole CLI command
 pub fn execute(arguments: Vec<&str>) -> Result<Output, std::io::Error> {
@@ -10,3 +19,128 @@ pub fn restart_dns() -> Result<Output, std::io::Error> {
     let restart_cmd: Vec<&str> = vec!["restartdns"];
     execute(restart_cmd)
 }
+
+/// Rewrite dnsmasq.d/01-pihole.conf from the DNS settings in setupVars.conf, which Pihole does
+/// when setting the listening mode, and restart the DNS service
+pub fn apply_dns_settings(listening_mode: &str) -> Result<Output, std::io::Error> {
+    execute(vec!["-a", "-i", listening_mode])
+}
+
+/// Test the dnsmasq configuration as pihole-FTL would load it, falling back to dnsmasq itself
+/// on setups without pihole-FTL
+pub fn test_dnsmasq_config() -> Result<Output, std::io::Error> {
+    match Command::new("pihole-FTL").arg("dnsmasq-test").output() {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("pihole-FTL not found, testing with dnsmasq");
+            Command::new("dnsmasq").arg("--test").output()
+        }
+        result => result,
+    }
+}
+
+/// Checks whether a gravity update (`pihole -g`) is currently running, by looking for the
+/// gravity script or the pihole command invoked with the update arguments in the process list
+pub fn is_gravity_updating() -> bool {
+    let proc_entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("could not read the process list: {}", e);
+            return false;
+        }
+    };
+
+    for entry in proc_entries.flatten() {
+        // processes can exit while being listed, so unreadable entries are skipped
+        let cmdline = match fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+
+        let args: Vec<String> = cmdline
+            .split(|b| *b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+
+        if is_gravity_update_cmd(&args) {
+            debug!(
+                "found running gravity update: {} ({})",
+                args.join(" "),
+                entry.file_name().to_string_lossy()
+            );
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Whether the given command line runs the gravity script, or the pihole command with the
+/// update argument, ex: `bash /opt/pihole/gravity.sh` or `/usr/local/bin/pihole -g`
+fn is_gravity_update_cmd(args: &[String]) -> bool {
+    let basename = |arg: &str| arg.rsplit('/').next().unwrap_or(arg).to_string();
+
+    let mut args = args.iter().map(|arg| basename(arg));
+    let mut command = args.next().unwrap_or_default();
+    if SHELLS.contains(&command.as_str()) {
+        command = args.next().unwrap_or_default();
+    }
+
+    match command.as_str() {
+        "gravity.sh" => true,
+        "pihole" => matches!(args.next().as_deref(), Some("-g" | "updateGravity")),
+        _ => false,
+    }
+}
+
+/// Blocks until a running gravity update finishes, or the timeout is reached. Returns whether
+/// the gravity update finished in time.
+pub fn wait_for_gravity_update(timeout: Duration) -> bool {
+    let started = Instant::now();
+    while is_gravity_updating() {
+        if started.elapsed() >= timeout {
+            return false;
+        }
+
+        info!(
+            "waiting for the running gravity update to finish ({}s elapsed)",
+            started.elapsed().as_secs()
+        );
+        thread::sleep(Duration::from_secs(GRAVITY_UPDATE_POLL_INTERVAL_SECS));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmdline(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn gravity_updates_are_recognised() {
+        for line in [
+            "/bin/bash /opt/pihole/gravity.sh",
+            "/opt/pihole/gravity.sh --force",
+            "/bin/bash /usr/local/bin/pihole -g",
+            "pihole updateGravity",
+        ] {
+            assert!(is_gravity_update_cmd(&cmdline(line)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn other_commands_mentioning_gravity_are_not() {
+        for line in [
+            "less /opt/pihole/gravity.sh",
+            "vim /usr/local/bin/pihole -g",
+            "/usr/local/bin/pihole -q -g",
+            "/usr/local/bin/pihole restartdns",
+            "grep -g pihole",
+            "",
+        ] {
+            assert!(!is_gravity_update_cmd(&cmdline(line)), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::pihole::wildcard;
+use log::info;
+use std::fmt;
+
+/// A `KEY=value` configuration file as used by setupVars.conf and pihole-FTL.conf. Lines other
+/// than key/value pairs (comments, blank lines) are kept as they are.
+#[derive(Debug, Default)]
+pub struct ConfFile {
+    lines: Vec<ConfLine>,
+}
+
+#[derive(Debug)]
+enum ConfLine {
+    Entry { key: String, value: String },
+    Other(String),
+}
+
+/// A key whose value differs between the archive and the target
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: String,
+}
+
+impl fmt::Display for KeyChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.old {
+            Some(old) => write!(f, "~ {}: {} -> {}", self.key, old, self.new),
+            None => write!(f, "+ {}={}", self.key, self.new),
+        }
+    }
+}
+
+/// Decides which keys are taken from the archive. A key is taken if it matches any of the
+/// allowed patterns and none of the denied patterns, so denying wins over allowing.
+#[derive(Debug, Clone)]
+pub struct KeyPolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl KeyPolicy {
+    /// Builds a policy from comma separated lists of key patterns, ex: `PIHOLE_DNS_*,DNSSEC`
+    pub fn from_lists(allow: &str, deny: &str) -> KeyPolicy {
+        KeyPolicy {
+            allow: wildcard::split_list(allow),
+            deny: wildcard::split_list(deny),
+        }
+    }
+
+    pub fn permits(&self, key: &str) -> bool {
+        wildcard::matches_any(&self.allow, key) && !wildcard::matches_any(&self.deny, key)
+    }
+}
+
+impl ConfFile {
+    pub fn parse(contents: &str) -> ConfFile {
+        let lines = contents
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                match trimmed.split_once('=') {
+                    Some((key, value)) if !trimmed.starts_with('#') && !key.trim().is_empty() => {
+                        ConfLine::Entry {
+                            key: key.trim().to_string(),
+                            value: value.trim().to_string(),
+                        }
+                    }
+                    _ => ConfLine::Other(line.to_string()),
+                }
+            })
+            .collect();
+        ConfFile { lines }
+    }
+
+    /// Returns the key/value pairs in the file, in order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            ConfLine::Entry { key, value } => Some((key.as_str(), value.as_str())),
+            ConfLine::Other(_) => None,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        // like the shell sourcing the file, the last occurrence wins
+        self.entries()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+            .last()
+    }
+
+    /// Removes the key/value pairs for which the given predicate returns false
+    pub fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut keep: F) {
+        self.lines.retain(|line| match line {
+            ConfLine::Entry { key, value } => keep(key, value),
+            ConfLine::Other(_) => true,
+        });
+    }
+
+    /// Sets the given key, replacing the value in place if it exists or appending it if not
+    pub fn set(&mut self, key: &str, value: &str) {
+        let mut found = false;
+        for line in self.lines.iter_mut() {
+            if let ConfLine::Entry { key: k, value: v } = line {
+                if k == key {
+                    *v = value.to_string();
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            self.lines.push(ConfLine::Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+            });
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                ConfLine::Entry { key, value } => {
+                    contents.push_str(key);
+                    contents.push('=');
+                    contents.push_str(value);
+                }
+                ConfLine::Other(other) => contents.push_str(other),
+            }
+            contents.push('\n');
+        }
+        contents
+    }
+
+    /// Takes the values of the keys permitted by the given policy from the incoming file,
+    /// returning the keys that changed. Keys that only exist in this file are left untouched.
+    pub fn merge(&mut self, incoming: &ConfFile, policy: &KeyPolicy) -> Vec<KeyChange> {
+        let mut changes: Vec<KeyChange> = Vec::new();
+        for (key, value) in incoming.entries() {
+            if !policy.permits(key) || changes.iter().any(|c| c.key == key) {
+                continue;
+            }
+
+            let new = incoming.get(key).unwrap_or(value).to_string();
+            let old = self.get(key).map(String::from);
+            if old.as_deref() == Some(new.as_str()) {
+                continue;
+            }
+
+            self.set(key, &new);
+            changes.push(KeyChange {
+                key: key.to_string(),
+                old,
+                new,
+            });
+        }
+        changes
+    }
+}
+
+/// Logs the given key level changes of the given file. Values of secrets are not printed.
+pub fn log_changes(file_name: &str, changes: &[KeyChange]) {
+    for change in changes {
+        if is_secret(&change.key) {
+            info!("{}: ~ {}: <redacted>", file_name, change.key);
+        } else {
+            info!("{}: {}", file_name, change);
+        }
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    key.contains("PASSWORD") || key.contains("TOKEN")
+}
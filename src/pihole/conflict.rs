@@ -0,0 +1,41 @@
+use clap::ValueEnum;
+use std::fmt;
+
+/// What to do with an archived entry that conflicts with an entry on this setup
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// keep the existing entry and skip the archived one
+    #[value(alias = "keep")]
+    Skip,
+    /// replace the existing entry with the archived one
+    Replace,
+    /// stop restoring the section without changing anything
+    Fail,
+}
+
+/// Number of archived entries per outcome of comparing them to the entries on this setup
+#[derive(Debug, Default)]
+pub struct Outcomes {
+    pub added: i32,
+    pub duplicates: i32,
+    pub kept: i32,
+    pub replaced: i32,
+    pub rejected: i32,
+}
+
+impl Outcomes {
+    /// Number of entries written to this setup
+    pub fn changed(&self) -> i32 {
+        self.added + self.replaced
+    }
+}
+
+impl fmt::Display for Outcomes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} duplicates skipped, {} conflicts kept, {} replaced, {} rejected",
+            self.added, self.duplicates, self.kept, self.replaced, self.rejected
+        )
+    }
+}
@@ -1,170 +1,782 @@
-use crate::pihole::cli;
+use crate::pihole::conflict::{ConflictPolicy, Outcomes};
+use crate::pihole::domain;
+use crate::pihole::files;
+use crate::pihole::setupvars;
 use flate2::read::GzDecoder;
-use log::{debug, warn};
-use regex::Regex;
+use log::{debug, info, warn};
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io::Read;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const STATIC_DHCP_CONF_FILE: &str = "/etc/dnsmasq.d/04-pihole-static-dhcp.conf";
+const DHCP_LEASES_FILE: &str = "/etc/pihole/dhcp.leases";
 
+/// A `dhcp-host` option of dnsmasq, ex: `dhcp-host=aa:bb:cc:dd:ee:ff,set:lab,10.0.0.5,nas,12h`
+/// https://thekelleys.org.uk/dnsmasq/docs/dnsmasq-man.html
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DhcpHost {
+    /// lowercase and colon separated
+    pub macs: Vec<String>,
+    /// `id:<client_id>`, or `id:*` to ignore the client identifier
+    pub client_id: Option<String>,
+    /// `set:<tag>`
+    pub set_tags: Vec<String>,
+    /// `tag:<tag>`
+    pub match_tags: Vec<String>,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub hostname: Option<String>,
+    /// `infinite` or a number of seconds with an optional `m`, `h`, `d` or `w` unit
+    pub lease_time: Option<String>,
+    /// `ignore`, the host is not given a lease
+    pub ignore: bool,
+}
+
+impl DhcpHost {
+    /// Parses the value of a `dhcp-host` option, with or without the `dhcp-host=` prefix.
+    /// Constructs this can't restore faithfully are returned as errors.
+    pub fn parse(line: &str) -> Result<DhcpHost, String> {
+        let value = line.trim();
+        let value = value.strip_prefix("dhcp-host=").unwrap_or(value);
+
+        let mut host = DhcpHost::default();
+        for field in value.split(',').map(str::trim) {
+            if field.is_empty() {
+                return Err(String::from("empty field"));
+            } else if let Some(id) = field.strip_prefix("id:") {
+                if host.client_id.is_some() {
+                    return Err(String::from("more than one client identifier"));
+                }
+                host.client_id = Some(id.to_string());
+            } else if let Some(tag) = field.strip_prefix("set:") {
+                host.set_tags.push(tag.to_string());
+            } else if let Some(tag) = field.strip_prefix("tag:") {
+                host.match_tags.push(tag.to_string());
+            } else if field == "ignore" {
+                host.ignore = true;
+            } else if field.starts_with('[') {
+                let ip = field
+                    .strip_prefix('[')
+                    .and_then(|f| f.strip_suffix(']'))
+                    .and_then(|f| f.parse::<Ipv6Addr>().ok())
+                    .ok_or_else(|| format!("invalid IPv6 address {}", field))?;
+                if host.ipv6.replace(ip).is_some() {
+                    return Err(String::from("more than one IPv6 address"));
+                }
+            } else if let Ok(ip) = field.parse::<Ipv4Addr>() {
+                if host.ipv4.replace(ip).is_some() {
+                    return Err(String::from("more than one IPv4 address"));
+                }
+            } else if field.parse::<Ipv6Addr>().is_ok() {
+                return Err(format!(
+                    "IPv6 address {} has to be enclosed in brackets",
+                    field
+                ));
+            } else if let Some(mac) = normalise_mac(field) {
+                host.macs.push(mac);
+            } else if field.contains(':') {
+                return Err(format!(
+                    "unsupported hardware address {}, only plain 6 byte MAC addresses are supported",
+                    field
+                ));
+            } else if is_lease_time(field) {
+                if host.lease_time.replace(field.to_string()).is_some() {
+                    return Err(String::from("more than one lease time"));
+                }
+            } else if is_legal_hostname(field) {
+                if host.hostname.replace(field.to_string()).is_some() {
+                    return Err(String::from("more than one host name"));
+                }
+            } else {
+                return Err(format!("unsupported field {}", field));
+            }
+        }
+
+        if host.macs.is_empty() && host.client_id.is_none() {
+            return Err(String::from("no MAC address or client identifier"));
+        }
+        Ok(host)
+    }
+}
+
+/// Renders the option with the fields in a fixed order, ex: `dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,nas`
+impl fmt::Display for DhcpHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields: Vec<String> = self.macs.clone();
+        if let Some(id) = &self.client_id {
+            fields.push(format!("id:{}", id));
+        }
+        fields.extend(self.set_tags.iter().map(|tag| format!("set:{}", tag)));
+        fields.extend(self.match_tags.iter().map(|tag| format!("tag:{}", tag)));
+        if let Some(ip) = &self.ipv4 {
+            fields.push(ip.to_string());
+        }
+        if let Some(ip) = &self.ipv6 {
+            fields.push(format!("[{}]", ip));
+        }
+        if let Some(hostname) = &self.hostname {
+            fields.push(hostname.clone());
+        }
+        if let Some(lease_time) = &self.lease_time {
+            fields.push(lease_time.clone());
+        }
+        if self.ignore {
+            fields.push(String::from("ignore"));
+        }
+        write!(f, "dhcp-host={}", fields.join(","))
+    }
+}
+
+/// A lease handed out by the DHCP server of this setup, as listed in dhcp.leases
+#[derive(Debug)]
+struct ActiveLease {
+    /// lowercase and colon separated, none for DHCPv6 leases which are listed by IAID
+    mac: Option<String>,
+    ip: IpAddr,
+    hostname: Option<String>,
+}
+
+impl fmt::Display for ActiveLease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {})",
+            self.ip,
+            self.mac.as_deref().unwrap_or("no MAC"),
+            self.hostname.as_deref().unwrap_or("no host name")
+        )
+    }
+}
+
+/// A line of the static DHCP config
+#[derive(Debug, Clone)]
+enum DhcpConfLine {
+    /// a `dhcp-host` option, rendered with its original text
+    Host(DhcpHost, String),
+    /// comments, blank lines, other options, and options that could not be parsed, which are
+    /// all kept as they are
+    Other(String),
+}
+
+/// The lines of a dnsmasq config file with `dhcp-host` options
+#[derive(Debug, Default)]
+struct StaticDhcpConf {
+    lines: Vec<DhcpConfLine>,
+}
+
+impl StaticDhcpConf {
+    /// Parses the given config contents, logging the options that could not be parsed with
+    /// their line number
+    fn parse(contents: &str, source: &str) -> StaticDhcpConf {
+        let mut conf = StaticDhcpConf::default();
+        for (index, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if !trimmed.starts_with("dhcp-host=") {
+                if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                    warn!(
+                        "unsupported option in {} at line {}: {}",
+                        source,
+                        index + 1,
+                        line
+                    );
+                }
+                conf.lines.push(DhcpConfLine::Other(line.to_string()));
+                continue;
+            }
+
+            match DhcpHost::parse(trimmed) {
+                Ok(host) => conf.lines.push(DhcpConfLine::Host(host, line.to_string())),
+                Err(reason) => {
+                    warn!(
+                        "invalid dhcp-host in {} at line {}: {} ({})",
+                        source,
+                        index + 1,
+                        line,
+                        reason
+                    );
+                    conf.lines.push(DhcpConfLine::Other(line.to_string()));
+                }
+            }
+        }
+        conf
+    }
+
+    fn hosts(&self) -> impl Iterator<Item = &DhcpHost> {
+        self.lines.iter().filter_map(|line| match line {
+            DhcpConfLine::Host(host, _) => Some(host),
+            DhcpConfLine::Other(_) => None,
+        })
+    }
+
+    /// Removes the lines of the given host
+    fn remove(&mut self, host: &DhcpHost) {
+        self.lines.retain(|line| match line {
+            DhcpConfLine::Host(h, _) => h != host,
+            DhcpConfLine::Other(_) => true,
+        });
+    }
+
+    fn push(&mut self, host: DhcpHost) {
+        let text = host.to_string();
+        self.lines.push(DhcpConfLine::Host(host, text));
+    }
+
+    fn render(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                DhcpConfLine::Host(_, text) | DhcpConfLine::Other(text) => contents.push_str(text),
+            }
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+/// Restores the `dhcp-host` options in the archived static DHCP config. Hosts sharing a MAC
+/// address, client identifier, IP address or host name with a host already configured on this
+/// setup are resolved by the given policy. Returns the number of hosts added or replaced.
 pub fn process_static_dhcp(
     file: &mut tar::Entry<'_, GzDecoder<File>>,
     flush: bool,
+    policy: ConflictPolicy,
+    skip_leased: bool,
 ) -> Result<i32, Box<dyn Error>> {
-    // https://github.com/pi-hole/pi-hole/blob/d885e92674e8d8d9a673b35ae706b2c49ea05840/advanced/Scripts/webpage.sh#L537
-    // this inserts different formats according to given input, so it's possible the backup could
-    // contain static dhcp entries of these types
-    // Pihole's Admin page Teleporer code is buggy when partial information is specified
-    enum StaticDHCPType {
-        Full,           // when all three are defined
-        StaticIP,       // when no host name is defined
-        StaticHostName, // when no IP address is defined
-    }
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let incoming = StaticDhcpConf::parse(&s, "archived static dhcp config");
 
     // trying to follow the same logic as Pihole teleporter PHP
-    if flush && Path::new(STATIC_DHCP_CONF_FILE).exists() {
+    let mut current = if flush {
         debug!("flushing existing static dhcp configuration");
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(STATIC_DHCP_CONF_FILE)
-        {
-            Err(e) => warn!("error while opening static dhcp config to flush: {}", e),
-            Ok(file) => match file.set_len(0) {
-                Err(e) => {
-                    warn!("error while truncating static dhcp config file: {}", e)
-                }
-                Ok(_) => debug!("static dhcp config truncated successfully"),
-            },
-        }
+        StaticDhcpConf::default()
+    } else {
+        StaticDhcpConf::parse(
+            &files::read_or_empty(STATIC_DHCP_CONF_FILE)?,
+            STATIC_DHCP_CONF_FILE,
+        )
+    };
+
+    let outcomes = merge_hosts(
+        &mut current,
+        &incoming,
+        &read_active_leases()?,
+        read_dhcp_range()?,
+        policy,
+        skip_leased,
+    )?;
+    if outcomes.changed() > 0 || flush {
+        files::write_atomically(STATIC_DHCP_CONF_FILE, &current.render())?;
     }
 
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
-
-    let mut processed_count = 0;
-    for entry in s.lines() {
-        debug!("processing static dhcp lease: {}", entry);
-        // dhcp-host=<MAC_ADDR>,<IP>,<HOSTNAME>  or variants where ip or hostname is missing
-        let sections: Vec<&str> = entry.split(',').collect();
-
-        let mode: StaticDHCPType;
-        if sections.len() == 3 {
-            mode = StaticDHCPType::Full;
-        } else if sections.len() == 2 {
-            // check if the second part is a valid ip
-            if is_valid_ip_addr(sections[1]) {
-                mode = StaticDHCPType::StaticIP;
-            } else {
-                mode = StaticDHCPType::StaticHostName;
-            }
-        } else {
-            warn!("invalid dhcp lease entry found: {}", entry);
+    // the DNS service is restarted once the whole archive is processed and validated
+    Ok(outcomes.changed())
+}
+
+/// Merges the given archived hosts into the given config, resolving the hosts that conflict
+/// with a configured one by the given policy. Hosts colliding with an active lease of another
+/// client are skipped when `skip_leased` is set.
+fn merge_hosts(
+    current: &mut StaticDhcpConf,
+    incoming: &StaticDhcpConf,
+    active_leases: &[ActiveLease],
+    dhcp_range: Option<(Ipv4Addr, Ipv4Addr)>,
+    policy: ConflictPolicy,
+    skip_leased: bool,
+) -> Result<Outcomes, Box<dyn Error>> {
+    let mut leased_count = 0;
+
+    let mut outcomes = Outcomes::default();
+    for host in incoming.hosts() {
+        debug!("processing static dhcp lease: {}", host);
+        if current.hosts().any(|existing| existing == host) {
+            debug!("static dhcp lease already exists: {}", host);
+            outcomes.duplicates += 1;
             continue;
         }
 
-        // extract MAC address from the first slice
-        match get_mac_addr(sections[0]) {
-            Some(addr) => {
-                let dhcp_added = match mode {
-                    StaticDHCPType::Full => {
-                        add_static_dhcp_entry(addr.as_str(), sections[1], sections[2], flush)?
-                    }
-                    StaticDHCPType::StaticIP => {
-                        add_static_dhcp_entry(addr.as_str(), sections[1], "nohost", flush)?
-                    }
-                    StaticDHCPType::StaticHostName => {
-                        add_static_dhcp_entry(addr.as_str(), "noip", sections[1], flush)?
-                    }
-                };
+        // reserving an address leased to another client causes an address conflict on the
+        // network until that lease expires
+        let leased: Vec<&ActiveLease> = active_leases
+            .iter()
+            .filter(|lease| leased_to_other_client(host, lease))
+            .collect();
+        for lease in &leased {
+            warn!(
+                "static dhcp lease {} collides with the active lease {} of another client",
+                host, lease
+            );
+        }
+        if !leased.is_empty() && skip_leased {
+            warn!("static dhcp: skipping {} since it is actively leased", host);
+            leased_count += 1;
+            continue;
+        }
 
-                if dhcp_added {
-                    processed_count += 1;
-                    debug!("dhcp entry succesfully added: {}", entry);
-                } else {
-                    warn!("could not add the dhcp entry: {}", entry);
+        let conflicting: Vec<(DhcpHost, Vec<&str>)> = current
+            .hosts()
+            .map(|existing| (existing.clone(), conflicts(existing, host)))
+            .filter(|(_, reasons)| !reasons.is_empty())
+            .collect();
+
+        for (existing, reasons) in &conflicting {
+            warn!(
+                "static dhcp conflict on {}: {} (archive) and {} (existing)",
+                reasons.join(", "),
+                host,
+                existing
+            );
+        }
+
+        if !conflicting.is_empty() {
+            match policy {
+                ConflictPolicy::Skip => {
+                    warn!(
+                        "static dhcp: keeping the existing entries, skipping {}",
+                        host
+                    );
+                    outcomes.kept += 1;
+                    continue;
+                }
+                ConflictPolicy::Fail => {
+                    return Err(Box::<dyn Error>::from(format!(
+                        "static dhcp lease {} conflicts with {} existing entries, not restoring any",
+                        host,
+                        conflicting.len()
+                    )));
+                }
+                ConflictPolicy::Replace => {
+                    for (existing, _) in &conflicting {
+                        info!("static dhcp: - {}", existing);
+                        current.remove(existing);
+                    }
+                    outcomes.replaced += 1;
                 }
             }
-            None => warn!(
-                "non-existent or invalid mac address found in the dhcp lease entry: {}",
-                entry
-            ),
+        } else {
+            outcomes.added += 1;
         }
-    }
 
-    match cli::restart_dns() {
-        Ok(_) => {
-            debug!("restarted dns service after loading static dhcp entries");
-            Ok(processed_count)
+        if let (Some(ip), Some((start, end))) = (host.ipv4, dhcp_range) {
+            if ip < start || ip > end {
+                warn!(
+                    "static dhcp lease {} is outside the DHCP range {}-{} of this setup",
+                    host, start, end
+                );
+            }
         }
-        Err(e) => {
-            warn!(
-                "error while restarting dns service after loading static dhcp entries: {}",
-                e
-            );
-            Err(Box::new(e))
+
+        info!("static dhcp: + {}", host);
+        current.push(host.clone());
+    }
+
+    info!("static dhcp: {}", outcomes);
+    if leased_count > 0 {
+        info!(
+            "static dhcp: {} skipped since they are actively leased",
+            leased_count
+        );
+    }
+    Ok(outcomes)
+}
+
+/// Returns what the given hosts have in common that makes them conflict: a MAC address, client
+/// identifier, IP address, or host name
+fn conflicts(a: &DhcpHost, b: &DhcpHost) -> Vec<&'static str> {
+    let mut reasons: Vec<&str> = Vec::new();
+    if a.macs.iter().any(|mac| b.macs.contains(mac)) {
+        reasons.push("MAC address");
+    }
+    if a.client_id.is_some() && a.client_id.as_deref() != Some("*") && a.client_id == b.client_id {
+        reasons.push("client identifier");
+    }
+    if a.ipv4.is_some() && a.ipv4 == b.ipv4 {
+        reasons.push("IPv4 address");
+    }
+    if a.ipv6.is_some() && a.ipv6 == b.ipv6 {
+        reasons.push("IPv6 address");
+    }
+    if let (Some(a_name), Some(b_name)) = (&a.hostname, &b.hostname) {
+        if a_name.eq_ignore_ascii_case(b_name) {
+            reasons.push("host name");
         }
     }
+    reasons
 }
 
-/// Extract a MAC address from the given string if it contains one
-fn get_mac_addr(s: &str) -> Option<regex::Match> {
-    let mac_pattern = r"([0-9a-fA-F]{2}:){5}[0-9a-fA-F]{2}";
-    let mac_regex = Regex::new(mac_pattern).unwrap();
+/// Returns the host names the DHCP server of this setup knows, from the static DHCP config and
+/// the active leases. With a local domain configured (`PIHOLE_DOMAIN`), the names are also
+/// known within that domain.
+pub fn hostnames() -> Result<Vec<String>, Box<dyn Error>> {
+    let conf = StaticDhcpConf::parse(
+        &files::read_or_empty(STATIC_DHCP_CONF_FILE)?,
+        STATIC_DHCP_CONF_FILE,
+    );
+    let mut names: Vec<String> = conf.hosts().filter_map(|h| h.hostname.clone()).collect();
+    names.extend(read_active_leases()?.into_iter().filter_map(|l| l.hostname));
 
-    // dhcp-host=<MAC_ADDR>
-    mac_regex.find(s)
+    if let Some(domain) = setupvars::read_setupvars()?.get("PIHOLE_DOMAIN") {
+        let qualified: Vec<String> = names
+            .iter()
+            .map(|name| format!("{}.{}", name, domain))
+            .collect();
+        names.extend(qualified);
+    }
+    Ok(names)
 }
 
-/// Checks if the given string is a valid IPv4 or IPv6 address
-fn is_valid_ip_addr(s: &str) -> bool {
-    let ipv4_pattern = r"^(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)$";
-    let ipv4_regex = Regex::new(ipv4_pattern).unwrap();
-
-    let ipv6_pattern = r"^([0-9a-fA-F]{1,4}:){7}[0-9a-fA-F]{1,4}$";
-    let ipv6_regex = Regex::new(ipv6_pattern).unwrap();
+/// Whether the given active lease belongs to a client other than the given host, while having
+/// its address or host name
+fn leased_to_other_client(host: &DhcpHost, lease: &ActiveLease) -> bool {
+    let same_client = match &lease.mac {
+        Some(mac) => host.macs.contains(mac),
+        None => false,
+    };
+    if same_client {
+        return false;
+    }
 
-    ipv4_regex.is_match(s) || ipv6_regex.is_match(s)
+    let same_ip = match lease.ip {
+        IpAddr::V4(ip) => host.ipv4 == Some(ip),
+        IpAddr::V6(ip) => host.ipv6 == Some(ip),
+    };
+    let same_hostname = match (&host.hostname, &lease.hostname) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    };
+    same_ip || same_hostname
 }
 
-fn add_static_dhcp_entry(
-    mac: &str,
-    ip: &str,
-    hostname: &str,
-    flushed: bool,
-) -> Result<bool, Box<dyn Error>> {
-    // todo: sanitisation
+/// Reads the leases that haven't expired from the dnsmasq lease file of this setup. Lines are
+/// `<expiry> <MAC> <IP> <host name|*> <client id|*>`, where DHCPv6 leases have an IAID instead
+/// of the MAC and follow a `duid` line.
+fn read_active_leases() -> Result<Vec<ActiveLease>, Box<dyn Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
-    // check for duplicates, if the file was flushed, no need to check for duplicates
-    if Path::new(STATIC_DHCP_CONF_FILE).exists() && !flushed {
-        let mut file = File::open(STATIC_DHCP_CONF_FILE)?;
-        let mut s = String::new();
-        file.read_to_string(&mut s).unwrap();
+    let mut leases: Vec<ActiveLease> = Vec::new();
+    for (index, line) in files::read_or_empty(DHCP_LEASES_FILE)?.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0] == "duid" {
+            continue;
+        }
 
-        // assuming O(n+m) is enough here
-        if s.contains(mac) {
-            warn!(
-                "mac address already exists in the static dhcp config: {}",
-                mac
-            );
-            return Ok(false);
+        let parsed = match fields.as_slice() {
+            [expiry, mac, ip, hostname, ..] => expiry
+                .parse::<u64>()
+                .ok()
+                .zip(ip.parse::<IpAddr>().ok())
+                .map(|(expiry, ip)| (expiry, *mac, ip, *hostname)),
+            _ => None,
+        };
+        let (expiry, mac, ip, hostname) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                debug!(
+                    "invalid lease in {} at line {}: {}",
+                    DHCP_LEASES_FILE,
+                    index + 1,
+                    line
+                );
+                continue;
+            }
+        };
+
+        // an expiry of 0 is an infinite lease
+        if expiry != 0 && expiry <= now {
+            continue;
         }
+
+        leases.push(ActiveLease {
+            mac: normalise_mac(mac),
+            ip,
+            hostname: Some(hostname).filter(|name| *name != "*").map(String::from),
+        });
+    }
+    Ok(leases)
+}
+
+/// Reads the range of addresses the DHCP server of this setup leases, from setupVars.conf
+fn read_dhcp_range() -> Result<Option<(Ipv4Addr, Ipv4Addr)>, Box<dyn Error>> {
+    let setupvars = setupvars::read_setupvars()?;
+    let start = setupvars.get("DHCP_START").and_then(|ip| ip.parse().ok());
+    let end = setupvars.get("DHCP_END").and_then(|ip| ip.parse().ok());
+    Ok(start.zip(end))
+}
+
+/// Normalises a 6 byte MAC address to lowercase and colon separated, ex: `AA-BB-CC-DD-EE-FF`
+/// to `aa:bb:cc:dd:ee:ff`. Octets can be `*` wildcards, ex: `aa:bb:cc:*:*:*`.
+fn normalise_mac(s: &str) -> Option<String> {
+    let octets: Vec<&str> = s.split([':', '-']).collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| *o == "*" || (o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit())));
+    if valid {
+        Some(octets.join(":").to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Checks whether dnsmasq takes the given field as a host name, the way its `legal_hostname`
+/// does: letters and digits, with hyphens and underscores after the first character, ex:
+/// `my_host`, optionally followed by a domain
+fn is_legal_hostname(s: &str) -> bool {
+    let (name, domain) = match s.split_once('.') {
+        Some((name, domain)) => (name, Some(domain)),
+        None => (s, None),
+    };
+    let legal_name = !name.is_empty()
+        && name
+            .chars()
+            .enumerate()
+            .all(|(index, c)| c.is_ascii_alphanumeric() || (index > 0 && (c == '-' || c == '_')));
+    legal_name && domain.is_none_or(domain::is_valid_domain)
+}
+
+/// Whether the given field is a lease time, ex: `infinite`, `3600`, `45m` or `12h`
+fn is_lease_time(s: &str) -> bool {
+    if s == "infinite" {
+        return true;
     }
+    let digits = s.trim_end_matches(['s', 'm', 'h', 'd', 'w']);
+    s.len() - digits.len() <= 1 && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // add the entry through the pihole cmd
-    let add_cmd: Vec<&str> = vec!["-a", "addstaticdhcp", mac, ip, hostname];
-    let exec_result = cli::execute(add_cmd);
-    match exec_result {
-        Ok(_) => {
-            debug!("static dhcp entry added successfully: {}", mac);
-            Ok(true)
+    #[test]
+    fn fields_are_recognised_in_any_order() {
+        let host = DhcpHost::parse(
+            "dhcp-host=12h,nas,set:lab,10.0.0.5,[fd00::5],AA-BB-CC-DD-EE-FF,tag:known",
+        )
+        .unwrap();
+        assert_eq!(host.macs, vec!["aa:bb:cc:dd:ee:ff"]);
+        assert_eq!(host.set_tags, vec!["lab"]);
+        assert_eq!(host.match_tags, vec!["known"]);
+        assert_eq!(host.ipv4, Some(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(host.ipv6, Some("fd00::5".parse().unwrap()));
+        assert_eq!(host.hostname.as_deref(), Some("nas"));
+        assert_eq!(host.lease_time.as_deref(), Some("12h"));
+        assert_eq!(
+            host.to_string(),
+            "dhcp-host=aa:bb:cc:dd:ee:ff,set:lab,tag:known,10.0.0.5,[fd00::5],nas,12h"
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_have_to_be_bracketed() {
+        let host = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,[fd00::1:5]").unwrap();
+        assert_eq!(host.ipv6, Some("fd00::1:5".parse().unwrap()));
+        assert_eq!(host.to_string(), "dhcp-host=aa:bb:cc:dd:ee:ff,[fd00::1:5]");
+
+        let e = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,fd00::5").unwrap_err();
+        assert!(e.contains("brackets"), "{}", e);
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,[10.0.0.5]").is_err());
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,[fd00::5],[fd00::6]").is_err());
+    }
+
+    #[test]
+    fn client_identifiers_identify_a_host() {
+        let host = DhcpHost::parse("dhcp-host=id:01:02:03:04,10.0.0.7,printer").unwrap();
+        assert!(host.macs.is_empty());
+        assert_eq!(host.client_id.as_deref(), Some("01:02:03:04"));
+        assert_eq!(
+            host.to_string(),
+            "dhcp-host=id:01:02:03:04,10.0.0.7,printer"
+        );
+
+        let host = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,id:*,10.0.0.8").unwrap();
+        assert_eq!(host.client_id.as_deref(), Some("*"));
+
+        assert!(DhcpHost::parse("dhcp-host=id:01,id:02").is_err());
+        assert!(DhcpHost::parse("dhcp-host=10.0.0.7,printer").is_err());
+    }
+
+    #[test]
+    fn lease_time_follows_the_mac() {
+        let host = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,45m").unwrap();
+        assert_eq!(host.lease_time.as_deref(), Some("45m"));
+        assert_eq!(host.hostname, None);
+
+        for lease_time in ["infinite", "3600", "2d", "1w"] {
+            let line = format!("dhcp-host=aa:bb:cc:dd:ee:ff,{}", lease_time);
+            let host = DhcpHost::parse(&line).unwrap();
+            assert_eq!(host.lease_time.as_deref(), Some(lease_time));
         }
-        Err(e) => {
-            warn!("error while adding static dhcp entry: {}, {}", mac, e);
-            Err(Box::new(e))
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,12h,1d").is_err());
+    }
+
+    #[test]
+    fn a_second_host_name_is_rejected() {
+        let e = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,nas,backup").unwrap_err();
+        assert_eq!(e, "more than one host name");
+    }
+
+    #[test]
+    fn wildcard_macs_are_kept() {
+        let host = DhcpHost::parse("dhcp-host=AA:BB:CC:*:*:*,set:vendor").unwrap();
+        assert_eq!(host.macs, vec!["aa:bb:cc:*:*:*"]);
+        assert_eq!(host.to_string(), "dhcp-host=aa:bb:cc:*:*:*,set:vendor");
+
+        let host =
+            DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:01,aa:bb:cc:dd:ee:02,10.0.0.9").unwrap();
+        assert_eq!(host.macs.len(), 2);
+
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee,10.0.0.9").is_err());
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:f*,10.0.0.9").is_err());
+    }
+
+    #[test]
+    fn host_names_follow_dnsmasq_rules() {
+        let host = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,my_host").unwrap();
+        assert_eq!(host.hostname.as_deref(), Some("my_host"));
+        assert_eq!(
+            host.to_string(),
+            "dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,my_host"
+        );
+
+        let host = DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,nas-1.lan").unwrap();
+        assert_eq!(host.hostname.as_deref(), Some("nas-1.lan"));
+
+        for name in ["_host", "-host", "my host", "nas..lan"] {
+            let line = format!("dhcp-host=aa:bb:cc:dd:ee:ff,{}", name);
+            assert!(DhcpHost::parse(&line).is_err(), "{}", name);
         }
     }
+
+    #[test]
+    fn empty_fields_are_rejected() {
+        assert!(DhcpHost::parse("dhcp-host=aa:bb:cc:dd:ee:ff,,nas").is_err());
+        assert!(DhcpHost::parse("dhcp-host=").is_err());
+    }
+
+    fn conf(contents: &str) -> StaticDhcpConf {
+        StaticDhcpConf::parse(contents, "test")
+    }
+
+    fn host(line: &str) -> DhcpHost {
+        DhcpHost::parse(line).unwrap()
+    }
+
+    #[test]
+    fn hosts_sharing_an_identity_conflict() {
+        let existing = host("dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,[fd00::5],nas");
+        let same_mac = host("dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.6");
+        let same_ips = host("dhcp-host=11:22:33:44:55:66,10.0.0.5,[fd00::5]");
+        let same_name = host("dhcp-host=11:22:33:44:55:66,NAS");
+        let unrelated = host("dhcp-host=11:22:33:44:55:66,10.0.0.6,backup");
+        assert_eq!(conflicts(&existing, &same_mac), vec!["MAC address"]);
+        assert_eq!(
+            conflicts(&existing, &same_ips),
+            vec!["IPv4 address", "IPv6 address"]
+        );
+        assert_eq!(conflicts(&existing, &same_name), vec!["host name"]);
+        assert!(conflicts(&existing, &unrelated).is_empty());
+    }
+
+    #[test]
+    fn ignored_client_identifiers_do_not_conflict() {
+        let a = host("dhcp-host=id:*,aa:bb:cc:dd:ee:01");
+        let b = host("dhcp-host=id:*,aa:bb:cc:dd:ee:02");
+        assert!(conflicts(&a, &b).is_empty());
+
+        let a = host("dhcp-host=id:01:02,10.0.0.5");
+        let b = host("dhcp-host=id:01:02,10.0.0.6");
+        assert_eq!(conflicts(&a, &b), vec!["client identifier"]);
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_policy() {
+        let existing = "# static leases\ndhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,nas\n";
+        let incoming = conf(
+            "dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,nas\n\
+             dhcp-host=11:22:33:44:55:66,10.0.0.5,backup\n\
+             dhcp-host=11:22:33:44:55:77,10.0.0.7,printer\n",
+        );
+
+        let mut current = conf(existing);
+        let outcomes = merge_hosts(
+            &mut current,
+            &incoming,
+            &[],
+            None,
+            ConflictPolicy::Skip,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            (outcomes.duplicates, outcomes.kept, outcomes.added),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            current.render(),
+            "# static leases\n\
+             dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,nas\n\
+             dhcp-host=11:22:33:44:55:77,10.0.0.7,printer\n"
+        );
+
+        let mut current = conf(existing);
+        let outcomes = merge_hosts(
+            &mut current,
+            &incoming,
+            &[],
+            None,
+            ConflictPolicy::Replace,
+            false,
+        )
+        .unwrap();
+        assert_eq!((outcomes.replaced, outcomes.added), (1, 1));
+        assert_eq!(
+            current.render(),
+            "# static leases\n\
+             dhcp-host=11:22:33:44:55:66,10.0.0.5,backup\n\
+             dhcp-host=11:22:33:44:55:77,10.0.0.7,printer\n"
+        );
+
+        let mut current = conf(existing);
+        assert!(merge_hosts(
+            &mut current,
+            &incoming,
+            &[],
+            None,
+            ConflictPolicy::Fail,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn hosts_leased_to_another_client_can_be_skipped() {
+        let lease = ActiveLease {
+            mac: Some(String::from("99:99:99:99:99:99")),
+            ip: "10.0.0.5".parse().unwrap(),
+            hostname: Some(String::from("phone")),
+        };
+        let same_ip = host("dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5");
+        let same_name = host("dhcp-host=aa:bb:cc:dd:ee:ff,Phone");
+        let same_client = host("dhcp-host=99:99:99:99:99:99,10.0.0.5,phone");
+        assert!(leased_to_other_client(&same_ip, &lease));
+        assert!(leased_to_other_client(&same_name, &lease));
+        assert!(!leased_to_other_client(&same_client, &lease));
+
+        let incoming = conf("dhcp-host=aa:bb:cc:dd:ee:ff,10.0.0.5,nas\n");
+        let mut current = StaticDhcpConf::default();
+        let outcomes = merge_hosts(
+            &mut current,
+            &incoming,
+            &[lease],
+            None,
+            ConflictPolicy::Skip,
+            true,
+        )
+        .unwrap();
+        assert_eq!(outcomes.changed(), 0);
+        assert_eq!(current.render(), "");
+    }
 }
@@ -1,221 +1,717 @@
-use crate::pihole::cli;
+use crate::pihole::conflict::{ConflictPolicy, Outcomes};
+use crate::pihole::dhcp;
+use crate::pihole::domain;
+use crate::pihole::files;
+use crate::pihole::hosts::{self, HostsEntry, HostsFile, HostsLine};
 use flate2::read::GzDecoder;
-use log::{debug, warn};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 
-const CUSTOM_DNS_FILE: &str = "/etc/pihole/custom.list";
-const CNAME_CONFIG_FILE: &str = "/etc/dnsmasq.d/05-pihole-custom-cname.conf";
+pub const CUSTOM_DNS_FILE: &str = "/etc/pihole/custom.list";
+pub const CNAME_CONFIG_FILE: &str = "/etc/dnsmasq.d/05-pihole-custom-cname.conf";
 
+/// Longest chain of CNAME records dnsmasq follows when answering a query
+const MAX_CNAME_CHAIN: usize = 10;
+
+/// Restores the Local DNS records in the archived custom.list. Records already on this setup
+/// are skipped, and names that resolve to another address are resolved by the given policy.
+/// Lines mapping several names are added as one record per name. Comments in custom.list are
+/// kept, and when flushing, the comments of the archived file are restored along with the
+/// records. Returns the number of records added or replaced.
 pub fn process_local_dns_entries(
     file: &mut tar::Entry<'_, GzDecoder<File>>,
     flush: bool,
+    policy: ConflictPolicy,
 ) -> Result<i32, Box<dyn Error>> {
-    if flush && !flush_local_dns_entries()? {
-        warn!("could not flush local dns entries");
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let (incoming, rejected) = HostsFile::parse(&s);
+    for r in rejected {
+        warn!(
+            "invalid entry in the archived custom.list at line {}: {} ({})",
+            r.line_number, r.line, r.reason
+        );
     }
 
-    // todo: dedup
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
-    let incoming_dns_entries = get_local_dns_entries(&s);
-
-    let mut processed_count = 0;
-    for entry in incoming_dns_entries {
-        let add_cmd = vec!["-a", "addcustomdns", &entry.ip, &entry.domain, "false"];
-        match cli::execute(add_cmd) {
-            Ok(_) => {
-                debug!("added dns entry: {}->{}", entry.ip, entry.domain);
-                processed_count += 1;
+    let mut current = if flush {
+        debug!("flushing existing local dns entries");
+        HostsFile::default()
+    } else {
+        read_custom_list()?
+    };
+
+    let mut outcomes = Outcomes::default();
+    for line in incoming.lines {
+        match line {
+            HostsLine::Entry { entry, comment, .. } => {
+                add_local_dns_entry(&mut current, &entry, comment, policy, &mut outcomes)?
             }
-            Err(e) => warn!(
-                "error while adding dns entry {}-.{}: {}",
-                entry.ip, entry.domain, e
-            ),
+            HostsLine::Other(text) if flush => current.lines.push(HostsLine::Other(text)),
+            _ => {}
         }
     }
 
-    match cli::restart_dns() {
-        Ok(_) => {
-            debug!("restarted dns service after loading custom dns entries");
-            Ok(processed_count)
-        }
-        Err(e) => {
-            warn!(
-                "error while restarting dns service after loading custom dns entries: {}",
-                e
-            );
-            Err(Box::new(e))
-        }
+    info!("local dns: {}", outcomes);
+    if outcomes.changed() > 0 || flush {
+        files::write_atomically(CUSTOM_DNS_FILE, &current.render())?;
     }
+
+    // the DNS service is restarted once the whole archive is processed and validated
+    Ok(outcomes.changed())
 }
 
+/// Restores the Local CNAME records in the archived CNAME config. Records are validated first:
+/// records whose chain of targets loops or is longer than dnsmasq follows are rejected, as are
+/// records whose target is not a local name when rejecting dangling records. Records already
+/// on this setup are skipped, and domains pointing to another target are resolved by the given
+/// policy. Records for several names are added as one record per name. Returns the number of
+/// records added or replaced.
 pub fn process_local_cname_entries(
     file: &mut tar::Entry<'_, GzDecoder<File>>,
     flush: bool,
+    policy: ConflictPolicy,
+    reject_dangling: bool,
 ) -> Result<i32, Box<dyn Error>> {
-    if flush {
-        if !flush_cname_config()? {
-            warn!("could not flush existing cname config");
-        } else {
-            debug!("flushed existing cname config");
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let incoming = CnameConf::parse(&s, "archived cname config");
+
+    let mut current = if flush {
+        debug!("flushing existing cname config");
+        CnameConf::default()
+    } else {
+        CnameConf::parse(&files::read_or_empty(CNAME_CONFIG_FILE)?, CNAME_CONFIG_FILE)
+    };
+
+    let mut records: Vec<CnameRecord> = Vec::new();
+    for record in incoming.records() {
+        for name in &record.names {
+            records.push(CnameRecord {
+                names: vec![name.clone()],
+                target: record.target.clone(),
+                ttl: record.ttl,
+            });
         }
     }
 
-    // todo: dedup
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
-    let incoming_dns_entries = get_cname_entries(&s);
-
-    let mut processed_count = 0;
-    for entry in incoming_dns_entries {
-        let add_cmd = vec![
-            "-a",
-            "addcustomcname",
-            &entry.domain,
-            &entry.target,
-            "false",
-        ];
-        match cli::execute(add_cmd) {
-            Ok(_) => {
-                debug!("added cname entry: {}->{}", entry.domain, entry.target);
-                processed_count += 1;
-            }
-            Err(e) => warn!(
-                "error while adding cname entry {}-.{}: {}",
-                entry.domain, entry.target, e
-            ),
-        }
+    let mut outcomes = Outcomes::default();
+    let invalid = validate_cnames(&records, &current, reject_dangling)?;
+    outcomes.rejected = invalid.len() as i32;
+
+    for record in records.iter().filter(|r| !invalid.contains(&r.names[0])) {
+        add_cname_record(&mut current, record, policy, &mut outcomes)?;
+    }
+
+    info!("local cname: {}", outcomes);
+    if outcomes.changed() > 0 || flush {
+        files::write_atomically(CNAME_CONFIG_FILE, &current.render())?;
     }
 
-    match cli::restart_dns() {
-        Ok(_) => {
-            debug!("restarted dns service after loading custom cname entries");
-            Ok(processed_count)
+    // the DNS service is restarted once the whole archive is processed and validated
+    Ok(outcomes.changed())
+}
+
+/// Adds the given single name CNAME record to the given config. A name that already points to
+/// another target is resolved by the given policy.
+fn add_cname_record(
+    current: &mut CnameConf,
+    record: &CnameRecord,
+    policy: ConflictPolicy,
+    outcomes: &mut Outcomes,
+) -> Result<(), Box<dyn Error>> {
+    let name = &record.names[0];
+    match current.target_of(name) {
+        Some(target) if target.eq_ignore_ascii_case(&record.target) => {
+            debug!("cname entry already exists: {}", record);
+            outcomes.duplicates += 1;
+            return Ok(());
         }
-        Err(e) => {
+        Some(target) => {
             warn!(
-                "error while restarting dns service after loading custom cname entries: {}",
-                e
+                "cname conflict: {} (archive) but {} points to {} on this setup",
+                record, name, target
             );
-            Err(Box::new(e))
+            match policy {
+                ConflictPolicy::Skip => {
+                    outcomes.kept += 1;
+                    return Ok(());
+                }
+                ConflictPolicy::Fail => {
+                    return Err(Box::<dyn Error>::from(format!(
+                        "cname record {} conflicts with an existing record, not restoring any",
+                        record
+                    )));
+                }
+                ConflictPolicy::Replace => {
+                    info!("local cname: - {},{}", name, target);
+                    current.remove(name);
+                    outcomes.replaced += 1;
+                }
+            }
         }
+        None => outcomes.added += 1,
     }
+
+    info!("local cname: + {}", record);
+    current.push(record.clone());
+    Ok(())
 }
 
-fn flush_local_dns_entries() -> Result<bool, Box<dyn Error>> {
-    let current_entries = get_current_local_dns_entries()?;
-    for entry in current_entries {
-        // setting false at the end avoids pihole restarting dns for every command execution
-        let flush_cmd: Vec<&str> = vec!["-a", "removecustomdns", &entry.ip, &entry.domain, "false"];
-        match cli::execute(flush_cmd) {
-            Ok(_) => debug!("removed dns entry: {}->{}", entry.ip, entry.domain),
-            Err(e) => warn!(
-                "error while trying remove custom dns entry {}->{}: {}",
-                entry.ip, entry.domain, e
-            ),
+/// Checks the given single name CNAME records against the given current config and the names
+/// known locally, logging the problems found. Returns the names of the records to reject.
+fn validate_cnames(
+    records: &[CnameRecord],
+    current: &CnameConf,
+    reject_dangling: bool,
+) -> Result<HashSet<String>, Box<dyn Error>> {
+    // archived records take precedence over the current ones for the same name
+    let mut targets: HashMap<String, String> = HashMap::new();
+    for record in current.records() {
+        for name in &record.names {
+            targets.insert(lookup_name(name), lookup_name(&record.target));
         }
     }
-    Ok(true)
+    for record in records {
+        targets.insert(lookup_name(&record.names[0]), lookup_name(&record.target));
+    }
+
+    let known_names = local_names()?;
+
+    let mut invalid: HashSet<String> = HashSet::new();
+    for record in records {
+        let name = &record.names[0];
+        let problem = if !is_valid_cname_name(name) {
+            Some(format!("invalid domain {}", name))
+        } else if !is_valid_cname_name(&record.target) {
+            Some(format!("invalid target {}", record.target))
+        } else {
+            match follow_cname_chain(&targets, name) {
+                Err(chain) if chain[..chain.len() - 1].contains(&chain[chain.len() - 1]) => {
+                    Some(format!("chain loops: {}", chain.join(" -> ")))
+                }
+                Err(chain) => Some(format!(
+                    "chain is longer than {} records: {}",
+                    MAX_CNAME_CHAIN,
+                    chain.join(" -> ")
+                )),
+                Ok(end) if !known_names.contains(&end) => {
+                    let message = format!(
+                        "target {} does not resolve to a local DNS record, DHCP host name or /etc/hosts entry, dnsmasq won't answer for it",
+                        end
+                    );
+                    if reject_dangling {
+                        Some(message)
+                    } else {
+                        warn!("{}: {}", record, message);
+                        None
+                    }
+                }
+                Ok(_) => None,
+            }
+        };
+
+        if let Some(problem) = problem {
+            warn!("rejecting cname record {}: {}", record, problem);
+            invalid.insert(name.clone());
+        }
+    }
+    Ok(invalid)
 }
 
-#[derive(Debug)]
-struct CustomDNSEntry {
-    ip: String,
-    domain: String,
+/// Checks whether dnsmasq accepts the given name in a CNAME record, which can have underscores,
+/// ex: `_sip._tcp.lan`, and a trailing dot
+fn is_valid_cname_name(name: &str) -> bool {
+    domain::is_valid_domain(name.strip_suffix('.').unwrap_or(name))
 }
 
-/// Reads and returns the Local DNS entries currently in the Pihole setup
-fn get_current_local_dns_entries() -> Result<Vec<CustomDNSEntry>, Box<dyn Error>> {
-    let mut file = File::open(CUSTOM_DNS_FILE)?;
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
-    Ok(get_local_dns_entries(&s))
+/// The form names are compared in when following CNAME chains, lowercase without a trailing dot
+fn lookup_name(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_lowercase()
 }
 
-/// Reads and returns local DNS entries in the given custom.list file
-fn get_local_dns_entries(contents: &str) -> Vec<CustomDNSEntry> {
-    let mut entries: Vec<CustomDNSEntry> = Vec::new();
-    for entry in contents.lines() {
-        let sections: Vec<&str> = entry.split(' ').collect();
-        if sections.len() != 2 {
-            warn!(
-                "invalid entry found while reading existing custom.list file: {}",
-                entry
-            );
-            continue;
+/// Follows the CNAME records from the given name, returning the name the chain ends at. If the
+/// chain loops or is too long, the names followed are returned as the error.
+fn follow_cname_chain(
+    targets: &HashMap<String, String>,
+    name: &str,
+) -> Result<String, Vec<String>> {
+    let mut chain: Vec<String> = vec![lookup_name(name)];
+    while let Some(target) = targets.get(chain.last().unwrap()) {
+        let looped = chain.contains(target);
+        chain.push(target.clone());
+        // a chain of n records has n + 1 names
+        if looped || chain.len() > MAX_CNAME_CHAIN + 1 {
+            return Err(chain);
         }
+    }
+    Ok(chain.pop().unwrap())
+}
 
-        let dns_entry = CustomDNSEntry {
-            ip: sections[0].to_string(),
-            domain: sections[1].to_string(),
-        };
-        entries.push(dns_entry);
-    }
-    entries
-}
-
-fn flush_cname_config() -> Result<bool, Box<dyn Error>> {
-    // only flush if we can read the file
-    if let Ok(current_entries) = get_current_cname_config() {
-        for entry in current_entries {
-            // setting false at the end avoids pihole restarting dns for every command execution
-            let flush_cmd: Vec<&str> = vec![
-                "-a",
-                "removecustomcname",
-                &entry.domain,
-                &entry.target,
-                "false",
-            ];
-            match cli::execute(flush_cmd) {
-                Ok(_) => debug!("removed cname entry: {}->{}", entry.domain, entry.target),
-                Err(e) => warn!(
-                    "error while trying remove custom cname entry {}->{}: {}",
-                    entry.domain, entry.target, e
-                ),
+/// Returns the lowercase names dnsmasq answers for on this setup besides CNAME records: Local
+/// DNS records, /etc/hosts entries, and DHCP host names
+fn local_names() -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut names: HashSet<String> = HashSet::new();
+    for entry in read_custom_list()?.entries() {
+        names.extend(entry.names.iter().map(|n| n.to_lowercase()));
+    }
+    for entry in hosts::parse_hosts(&files::read_or_empty(hosts::HOSTS_FILE)?) {
+        names.extend(entry.names.iter().map(|n| n.to_lowercase()));
+    }
+    names.extend(dhcp::hostnames()?.iter().map(|n| n.to_lowercase()));
+    Ok(names)
+}
+
+/// Adds the given hosts file entries as Local DNS records, skipping the names that already
+/// have a record. Returns the number of records added.
+pub fn add_hosts_entries(entries: &[HostsEntry]) -> Result<i32, Box<dyn Error>> {
+    let mut current = read_custom_list()?;
+
+    let mut outcomes = Outcomes::default();
+    for entry in entries {
+        add_local_dns_entry(
+            &mut current,
+            entry,
+            None,
+            ConflictPolicy::Skip,
+            &mut outcomes,
+        )?;
+    }
+
+    info!("local dns: {}", outcomes);
+    if outcomes.changed() > 0 {
+        files::write_atomically(CUSTOM_DNS_FILE, &current.render())?;
+    }
+    Ok(outcomes.changed())
+}
+
+/// Adds a record for each name of the given entry to the given custom.list. Names that already
+/// resolve to another address of the same family are resolved by the given policy.
+fn add_local_dns_entry(
+    current: &mut HostsFile,
+    entry: &HostsEntry,
+    mut comment: Option<String>,
+    policy: ConflictPolicy,
+    outcomes: &mut Outcomes,
+) -> Result<(), Box<dyn Error>> {
+    for name in &entry.names {
+        match current.ip_of(name, &entry.ip) {
+            Some(ip) if ip == entry.ip => {
+                debug!("local dns entry already exists: {}->{}", entry.ip, name);
+                outcomes.duplicates += 1;
+                continue;
+            }
+            Some(ip) => {
+                warn!(
+                    "local dns conflict: {}->{} (archive) but {} resolves to {} on this setup",
+                    entry.ip, name, name, ip
+                );
+                match policy {
+                    ConflictPolicy::Skip => {
+                        outcomes.kept += 1;
+                        continue;
+                    }
+                    ConflictPolicy::Fail => {
+                        return Err(Box::<dyn Error>::from(format!(
+                            "local dns record {}->{} conflicts with an existing record, not restoring any",
+                            entry.ip, name
+                        )));
+                    }
+                    ConflictPolicy::Replace => {
+                        info!("local dns: - {} {}", ip, name);
+                        current.remove_name(name, &ip);
+                        outcomes.replaced += 1;
+                    }
+                }
             }
+            None => outcomes.added += 1,
         }
-        Ok(true)
-    } else {
-        Ok(false)
+
+        info!("local dns: + {} {}", entry.ip, name);
+        // one record per line like Pihole adds them, with the comment on the first one
+        current.push(
+            HostsEntry {
+                ip: entry.ip,
+                names: vec![name.clone()],
+            },
+            comment.take(),
+        );
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct CNameConfigEntry {
-    domain: String,
-    target: String,
+/// Reads the Local DNS records currently in the Pihole setup. Lines that can't be parsed are
+/// kept as they are when the file is written back.
+fn read_custom_list() -> Result<HostsFile, Box<dyn Error>> {
+    let (current, rejected) = HostsFile::parse(&files::read_or_empty(CUSTOM_DNS_FILE)?);
+    for r in rejected {
+        warn!(
+            "invalid entry in {} at line {}, keeping it as it is: {} ({})",
+            CUSTOM_DNS_FILE, r.line_number, r.line, r.reason
+        );
+    }
+    Ok(current)
 }
 
-/// Reads and returns the local CNAME configuration in the Pihole setup
-fn get_current_cname_config() -> Result<Vec<CNameConfigEntry>, Box<dyn Error>> {
-    let mut file = File::open(CNAME_CONFIG_FILE)?;
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
-    Ok(get_cname_entries(&s))
-}
-
-/// Reads and returns the CNAME configuration in the given dnsmasq configuration file
-fn get_cname_entries(contents: &str) -> Vec<CNameConfigEntry> {
-    let mut entries: Vec<CNameConfigEntry> = Vec::new();
-    for entry in contents.lines() {
-        //cname=<DOMAIN>,<TARGET>
-        let cleaned_entry = str::replace(entry, "cname=", "");
-        let sections: Vec<&str> = cleaned_entry.split(',').collect();
-        if sections.len() != 2 {
-            warn!(
-                "invalid entry found while reading existing cname config file: {}",
-                entry
-            );
-            continue;
+/// A `cname=<CNAME>[,<CNAME>...],<TARGET>[,<TTL>]` option
+/// https://thekelleys.org.uk/dnsmasq/docs/dnsmasq-man.html
+#[derive(Debug, Clone)]
+pub struct CnameRecord {
+    pub names: Vec<String>,
+    pub target: String,
+    pub ttl: Option<u32>,
+}
+
+impl CnameRecord {
+    /// Parses a `cname` option, returning none if it isn't one or is malformed
+    pub fn parse(line: &str) -> Option<CnameRecord> {
+        let value = line.trim().strip_prefix("cname=")?;
+        let mut fields: Vec<&str> = value.split(',').map(str::trim).collect();
+        if fields.iter().any(|f| f.is_empty()) {
+            return None;
         }
 
-        let cname_entry = CNameConfigEntry {
-            domain: sections[0].to_string(),
-            target: sections[1].to_string(),
+        // a trailing number is the TTL, since names are never all digits
+        let ttl = match fields.last() {
+            Some(last) if fields.len() > 2 && last.chars().all(|c| c.is_ascii_digit()) => {
+                Some(last.parse::<u32>().ok()?)
+            }
+            _ => None,
         };
-        entries.push(cname_entry);
+        if ttl.is_some() {
+            fields.pop();
+        }
+
+        let target = fields.pop()?;
+        if fields.is_empty() {
+            return None;
+        }
+        Some(CnameRecord {
+            names: fields.into_iter().map(String::from).collect(),
+            target: target.to_string(),
+            ttl,
+        })
+    }
+}
+
+impl fmt::Display for CnameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cname={},{}", self.names.join(","), self.target)?;
+        if let Some(ttl) = self.ttl {
+            write!(f, ",{}", ttl)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum CnameLine {
+    /// a `cname` option, rendered with its original text
+    Record(CnameRecord, String),
+    /// comments, blank lines, and lines that could not be parsed, which are kept as they are
+    Other(String),
+}
+
+/// The lines of the CNAME config
+#[derive(Debug, Default)]
+struct CnameConf {
+    lines: Vec<CnameLine>,
+}
+
+impl CnameConf {
+    /// Parses the given config contents, logging the lines that could not be parsed with their
+    /// line number
+    fn parse(contents: &str, source: &str) -> CnameConf {
+        let mut conf = CnameConf::default();
+        for (index, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                conf.lines.push(CnameLine::Other(line.to_string()));
+                continue;
+            }
+
+            match CnameRecord::parse(trimmed) {
+                Some(record) => conf.lines.push(CnameLine::Record(record, line.to_string())),
+                None => {
+                    warn!(
+                        "invalid entry in {} at line {}: {}",
+                        source,
+                        index + 1,
+                        line
+                    );
+                    conf.lines.push(CnameLine::Other(line.to_string()));
+                }
+            }
+        }
+        conf
+    }
+
+    fn records(&self) -> impl Iterator<Item = &CnameRecord> {
+        self.lines.iter().filter_map(|line| match line {
+            CnameLine::Record(record, _) => Some(record),
+            CnameLine::Other(_) => None,
+        })
+    }
+
+    fn target_of(&self, name: &str) -> Option<String> {
+        self.records()
+            .find(|record| record.names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+            .map(|record| record.target.clone())
+    }
+
+    /// Removes the given name from the records, dropping the records left without names
+    fn remove(&mut self, name: &str) {
+        for line in self.lines.iter_mut() {
+            if let CnameLine::Record(record, text) = line {
+                let before = record.names.len();
+                record.names.retain(|n| !n.eq_ignore_ascii_case(name));
+                if record.names.len() != before {
+                    *text = record.to_string();
+                }
+            }
+        }
+        self.lines.retain(|line| match line {
+            CnameLine::Record(record, _) => !record.names.is_empty(),
+            CnameLine::Other(_) => true,
+        });
+    }
+
+    fn push(&mut self, record: CnameRecord) {
+        let text = record.to_string();
+        self.lines.push(CnameLine::Record(record, text));
+    }
+
+    fn render(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                CnameLine::Record(_, text) | CnameLine::Other(text) => contents.push_str(text),
+            }
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_targets(names: &[&str]) -> HashMap<String, String> {
+        names
+            .windows(2)
+            .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn trailing_number_is_the_ttl() {
+        let record = CnameRecord::parse("cname=a,b,t,300").unwrap();
+        assert_eq!(record.names, vec!["a", "b"]);
+        assert_eq!(record.target, "t");
+        assert_eq!(record.ttl, Some(300));
+        assert_eq!(record.to_string(), "cname=a,b,t,300");
+    }
+
+    #[test]
+    fn number_after_a_single_name_is_the_target() {
+        let record = CnameRecord::parse("cname=a,300").unwrap();
+        assert_eq!(record.names, vec!["a"]);
+        assert_eq!(record.target, "300");
+        assert_eq!(record.ttl, None);
+    }
+
+    #[test]
+    fn malformed_cname_options_are_not_parsed() {
+        assert!(CnameRecord::parse("cname=a").is_none());
+        assert!(CnameRecord::parse("cname=a,,t").is_none());
+        assert!(CnameRecord::parse("cname=a,t,99999999999").is_none());
+        assert!(CnameRecord::parse("address=/a/1.2.3.4").is_none());
+    }
+
+    #[test]
+    fn chain_ends_at_the_last_target() {
+        let targets = chain_targets(&["a.lan", "b.lan", "c.lan"]);
+        assert_eq!(
+            follow_cname_chain(&targets, "A.lan."),
+            Ok(String::from("c.lan"))
+        );
+    }
+
+    #[test]
+    fn two_record_loop_is_reported() {
+        let targets = chain_targets(&["a.lan", "b.lan", "a.lan"]);
+        assert_eq!(
+            follow_cname_chain(&targets, "a.lan"),
+            Err(vec![
+                String::from("a.lan"),
+                String::from("b.lan"),
+                String::from("a.lan")
+            ])
+        );
+    }
+
+    #[test]
+    fn chains_longer_than_the_limit_are_reported() {
+        let names: Vec<String> = (0..=11).map(|i| format!("n{}.lan", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        // 10 records are followed to the end
+        let targets = chain_targets(&names[..11]);
+        assert_eq!(
+            follow_cname_chain(&targets, "n0.lan"),
+            Ok(String::from("n10.lan"))
+        );
+
+        // 11 records are too many
+        let targets = chain_targets(&names);
+        let chain = follow_cname_chain(&targets, "n0.lan").unwrap_err();
+        assert_eq!(chain.len(), MAX_CNAME_CHAIN + 2);
+    }
+
+    #[test]
+    fn service_names_and_trailing_dots_are_valid() {
+        assert!(is_valid_cname_name("_sip._tcp.lan"));
+        assert!(is_valid_cname_name("target.lan."));
+        assert!(!is_valid_cname_name("a..lan"));
+        assert!(!is_valid_cname_name("a lan"));
+    }
+
+    fn custom_list(contents: &str) -> HostsFile {
+        HostsFile::parse(contents).0
+    }
+
+    fn hosts_entry(ip: &str, names: &[&str]) -> HostsEntry {
+        HostsEntry {
+            ip: ip.parse().unwrap(),
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn cname(line: &str) -> CnameRecord {
+        CnameRecord::parse(line).unwrap()
+    }
+
+    #[test]
+    fn local_dns_records_already_in_custom_list_are_skipped() {
+        let mut current = custom_list("# lan\n10.0.0.1 nas nas.lan\nfd00::1 nas\n");
+        let mut outcomes = Outcomes::default();
+        let entry = hosts_entry("10.0.0.1", &["NAS", "printer"]);
+        add_local_dns_entry(
+            &mut current,
+            &entry,
+            Some(String::from("office")),
+            ConflictPolicy::Fail,
+            &mut outcomes,
+        )
+        .unwrap();
+        assert_eq!((outcomes.duplicates, outcomes.added), (1, 1));
+        assert_eq!(
+            current.render(),
+            "# lan\n10.0.0.1 nas nas.lan\nfd00::1 nas\n10.0.0.1 printer # office\n"
+        );
+    }
+
+    #[test]
+    fn local_dns_conflicts_are_resolved_by_policy() {
+        let existing = "10.0.0.1 nas nas.lan\nfd00::1 nas\n";
+        let entry = hosts_entry("10.0.0.2", &["nas"]);
+
+        let mut current = custom_list(existing);
+        let mut outcomes = Outcomes::default();
+        add_local_dns_entry(
+            &mut current,
+            &entry,
+            None,
+            ConflictPolicy::Skip,
+            &mut outcomes,
+        )
+        .unwrap();
+        assert_eq!(outcomes.kept, 1);
+        assert_eq!(current.render(), existing);
+
+        // only the record of the same address family is replaced
+        let mut current = custom_list(existing);
+        let mut outcomes = Outcomes::default();
+        add_local_dns_entry(
+            &mut current,
+            &entry,
+            None,
+            ConflictPolicy::Replace,
+            &mut outcomes,
+        )
+        .unwrap();
+        assert_eq!(outcomes.replaced, 1);
+        assert_eq!(
+            current.render(),
+            "10.0.0.1 nas.lan\nfd00::1 nas\n10.0.0.2 nas\n"
+        );
+
+        let mut current = custom_list(existing);
+        let mut outcomes = Outcomes::default();
+        assert!(add_local_dns_entry(
+            &mut current,
+            &entry,
+            None,
+            ConflictPolicy::Fail,
+            &mut outcomes,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn cname_records_already_configured_are_skipped() {
+        let mut current = CnameConf::parse("cname=a.lan,b.lan,nas.lan\n", "test");
+        let mut outcomes = Outcomes::default();
+        for record in ["cname=B.lan,NAS.lan", "cname=c.lan,nas.lan"] {
+            add_cname_record(
+                &mut current,
+                &cname(record),
+                ConflictPolicy::Fail,
+                &mut outcomes,
+            )
+            .unwrap();
+        }
+        assert_eq!((outcomes.duplicates, outcomes.added), (1, 1));
+        assert_eq!(
+            current.render(),
+            "cname=a.lan,b.lan,nas.lan\ncname=c.lan,nas.lan\n"
+        );
+    }
+
+    #[test]
+    fn cname_conflicts_are_resolved_by_policy() {
+        let existing = "# lan\ncname=a.lan,b.lan,nas.lan\n";
+        let record = cname("cname=b.lan,printer.lan");
+
+        let mut current = CnameConf::parse(existing, "test");
+        let mut outcomes = Outcomes::default();
+        add_cname_record(&mut current, &record, ConflictPolicy::Skip, &mut outcomes).unwrap();
+        assert_eq!(outcomes.kept, 1);
+        assert_eq!(current.render(), existing);
+
+        let mut current = CnameConf::parse(existing, "test");
+        let mut outcomes = Outcomes::default();
+        add_cname_record(
+            &mut current,
+            &record,
+            ConflictPolicy::Replace,
+            &mut outcomes,
+        )
+        .unwrap();
+        assert_eq!(outcomes.replaced, 1);
+        assert_eq!(
+            current.render(),
+            "# lan\ncname=a.lan,nas.lan\ncname=b.lan,printer.lan\n"
+        );
+
+        let mut current = CnameConf::parse(existing, "test");
+        let mut outcomes = Outcomes::default();
+        assert!(
+            add_cname_record(&mut current, &record, ConflictPolicy::Fail, &mut outcomes).is_err()
+        );
     }
-    entries
 }
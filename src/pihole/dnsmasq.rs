@@ -0,0 +1,309 @@
+use crate::pihole::cli;
+use crate::pihole::dns;
+use crate::pihole::files;
+use crate::pihole::hosts;
+use crate::pihole::wildcard;
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+
+pub const DNSMASQ_DIR: &str = "/etc/dnsmasq.d";
+
+/// files under dnsmasq.d restored when the patterns are not specified
+pub const DEFAULT_FILE_PATTERNS: &str = "*.conf";
+
+/// files Pihole generates itself, which are either rewritten by Pihole on the next
+/// configuration change or restored by their own sections
+const MANAGED_FILES: [&str; 5] = [
+    "01-pihole.conf",
+    "02-pihole-dhcp.conf",
+    "03-pihole-wildcard.conf",
+    "04-pihole-static-dhcp.conf",
+    "05-pihole-custom-cname.conf",
+];
+
+/// Checks whether the given archive entry is an additional dnsmasq.d file, as opposed to one
+/// Pihole manages or one outside of dnsmasq.d, regardless of the file patterns
+pub fn is_extra_file(archive_path: &str) -> bool {
+    match archive_path.strip_prefix("dnsmasq.d/") {
+        Some(file_name) => {
+            !file_name.is_empty()
+                && !file_name.contains('/')
+                && !file_name.starts_with('.')
+                && !MANAGED_FILES.contains(&file_name)
+        }
+        None => false,
+    }
+}
+
+/// Restores an additional dnsmasq.d file from the archive, ex: `dnsmasq.d/02-custom-upstreams.conf`,
+/// if its name matches any of the given patterns. Files managed by Pihole are refused. Returns
+/// the number of lines that changed.
+pub fn restore_extra_file(
+    file: &mut tar::Entry<'_, GzDecoder<File>>,
+    archive_path: &str,
+    patterns: &[String],
+) -> Result<i32, Box<dyn Error>> {
+    let file_name = archive_path
+        .strip_prefix("dnsmasq.d/")
+        .unwrap_or(archive_path);
+
+    // only plain files directly under dnsmasq.d are written, never anywhere else
+    if file_name.is_empty() || file_name.contains('/') || file_name.starts_with('.') {
+        return Err(Box::<dyn Error>::from(format!(
+            "refusing to restore {} since it is not a file directly under dnsmasq.d",
+            archive_path
+        )));
+    }
+
+    if MANAGED_FILES.contains(&file_name) {
+        return Err(Box::<dyn Error>::from(format!(
+            "refusing to restore {} since it is managed by Pihole",
+            archive_path
+        )));
+    }
+
+    if !wildcard::matches_any(patterns, file_name) {
+        info!(
+            "not restoring {} since it does not match the dnsmasq.d file patterns",
+            archive_path
+        );
+        return Ok(0);
+    }
+
+    let mut incoming = String::new();
+    file.read_to_string(&mut incoming)?;
+
+    let target = format!("{}/{}", DNSMASQ_DIR, file_name);
+    let current = files::read_or_empty(&target)?;
+
+    let diff = diff_lines(&current, &incoming);
+    if diff.is_empty() {
+        info!("{} is already up to date", target);
+        return Ok(0);
+    }
+
+    for line in &diff {
+        info!("{}: {}", target, line);
+    }
+    files::write_atomically(&target, &incoming)?;
+    debug!("restored {} from {}", target, archive_path);
+
+    Ok(diff.len() as i32)
+}
+
+/// Contents of the files in dnsmasq.d and of the files dnsmasq reads records from before the
+/// restore, to roll back to if the restored configuration doesn't pass the dnsmasq config test
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    files: BTreeMap<String, String>,
+    /// whether the configuration passed the config test when the snapshot was taken, none if
+    /// the test could not be run
+    test_passed: Option<bool>,
+}
+
+impl Snapshot {
+    pub fn take() -> Result<Snapshot, Box<dyn Error>> {
+        let test_passed = match cli::test_dnsmasq_config() {
+            Ok(output) => Some(output.status.success()),
+            Err(e) => {
+                debug!("could not test the dnsmasq configuration: {}", e);
+                None
+            }
+        };
+        if test_passed == Some(false) {
+            warn!("the dnsmasq configuration does not pass the config test before the restore");
+        }
+
+        Ok(Snapshot {
+            files: read_config_files()?,
+            test_passed,
+        })
+    }
+
+    /// Whether the configuration already failed the config test when the snapshot was taken
+    pub fn failed_before(&self) -> bool {
+        self.test_passed == Some(false)
+    }
+
+    /// Returns the files that were added or changed since the snapshot was taken, or removed
+    pub fn changed_files(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let current = read_config_files()?;
+        let mut changed: Vec<String> = Vec::new();
+        for (path, contents) in &current {
+            if self.files.get(path) != Some(contents) {
+                changed.push(path.clone());
+            }
+        }
+        for path in self.files.keys() {
+            if !current.contains_key(path) {
+                changed.push(path.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Puts back the contents of the changed files, and removes the files that were added
+    pub fn restore(&self) -> Result<i32, Box<dyn Error>> {
+        let mut restored_count = 0;
+        for path in self.changed_files()? {
+            match self.files.get(&path) {
+                Some(contents) => files::write_atomically(&path, contents)?,
+                None => fs::remove_file(&path)?,
+            }
+            info!("rolled back {}", path);
+            restored_count += 1;
+        }
+        Ok(restored_count)
+    }
+}
+
+/// Reads the contents of the files dnsmasq loads that a restore writes to: the regular files in
+/// dnsmasq.d, custom.list and /etc/hosts, by path
+fn read_config_files() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut contents = read_dnsmasq_dir()?;
+    for path in [
+        dns::CUSTOM_DNS_FILE,
+        dns::CNAME_CONFIG_FILE,
+        hosts::HOSTS_FILE,
+    ] {
+        match fs::read_to_string(path) {
+            Ok(file_contents) => {
+                contents.insert(path.to_string(), file_contents);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    Ok(contents)
+}
+
+/// Reads the contents of the regular files in dnsmasq.d, by path
+fn read_dnsmasq_dir() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut contents: BTreeMap<String, String> = BTreeMap::new();
+    let entries = match fs::read_dir(DNSMASQ_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(contents),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = entry.path().to_string_lossy().to_string();
+        // temporary files of an interrupted atomic write are not part of the configuration
+        if path.ends_with(".pihole-restore.tmp") {
+            continue;
+        }
+        contents.insert(path.clone(), fs::read_to_string(&path)?);
+    }
+    Ok(contents)
+}
+
+/// Runs the dnsmasq config test if any file of the given snapshot changed since it was taken.
+/// If the test fails while it passed before the restore, the changed files are rolled back to
+/// the snapshot, before the DNS service is restarted with a configuration it would refuse. A
+/// configuration that already failed is left as restored, since the restore didn't break it.
+pub fn validate_changes(snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+    let changed = snapshot.changed_files()?;
+    if changed.is_empty() {
+        debug!("no dnsmasq configuration files changed, not testing the dnsmasq configuration");
+        return Ok(());
+    }
+
+    let output = match cli::test_dnsmasq_config() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!(
+                "could not test the dnsmasq configuration, changes to {} are not validated: {}",
+                changed.join(", "),
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    if output.status.success() {
+        info!("dnsmasq configuration test passed");
+        return Ok(());
+    }
+
+    let message = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    error!("dnsmasq configuration test failed: {}", message.trim());
+    if let Some((path, line_number)) = offending_line(&message) {
+        match fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| contents.lines().nth(line_number - 1).map(String::from))
+        {
+            Some(line) => error!("offending line {} of {}: {}", line_number, path, line),
+            None => error!("offending line {} of {}", line_number, path),
+        }
+    }
+
+    if snapshot.failed_before() {
+        return Err(Box::<dyn Error>::from(format!(
+            "dnsmasq configuration did not pass the config test before the restore either, not rolling back the changes to {}",
+            changed.join(", ")
+        )));
+    }
+
+    let restored_count = snapshot.restore()?;
+    Err(Box::<dyn Error>::from(format!(
+        "restored dnsmasq configuration did not pass the config test, rolled back {} files",
+        restored_count
+    )))
+}
+
+/// Extracts the file and line number from a dnsmasq error, ex:
+/// `dnsmasq: bad option at line 3 of /etc/dnsmasq.d/02-custom.conf`
+fn offending_line(message: &str) -> Option<(String, usize)> {
+    let line_regex = Regex::new(r"at line (\d+) of (\S+)").unwrap();
+    let captures = line_regex.captures(message)?;
+    let line_number = captures[1].parse::<usize>().ok().filter(|n| *n > 0)?;
+    Some((captures[2].to_string(), line_number))
+}
+
+/// Line level diff between the given contents, with removed lines prefixed by `-` and added
+/// lines by `+`. Lines in the longest common subsequence are left out.
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff: Vec<String> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
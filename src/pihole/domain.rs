@@ -0,0 +1,155 @@
+use std::net::Ipv4Addr;
+
+/// Longest host name allowed in DNS, without the trailing dot
+const MAX_HOSTNAME_LENGTH: usize = 253;
+
+/// Longest label allowed in DNS
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Checks whether the given name is a valid host name as per RFC 1123: dot separated labels of
+/// letters, digits and hyphens that don't start or end with a hyphen
+pub fn is_valid_hostname(name: &str) -> bool {
+    if name.is_empty() || name.len() > MAX_HOSTNAME_LENGTH {
+        return false;
+    }
+
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Checks whether the given name is a domain Pihole can match exactly. Unlike host names,
+/// labels can contain underscores and start or end with a hyphen, ex: `_dmarc.example.com`.
+pub fn is_valid_domain(name: &str) -> bool {
+    if name.is_empty() || name.len() > MAX_HOSTNAME_LENGTH {
+        return false;
+    }
+
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Turns a domain as it may have been typed or pasted into the form Pihole matches queries
+/// against: surrounding whitespace, a URL scheme, user info, port and path, and trailing dots
+/// are removed, and internationalised names are converted to lowercase punycode, ex:
+/// `https://Bücher.Example./shop` becomes `xn--bcher-kva.example`. Labels can't start or end
+/// with a hyphen, since no registry hands out such names.
+pub fn normalise_domain(input: &str) -> Result<String, String> {
+    let mut domain = input.trim();
+    if let Some((_, rest)) = domain.split_once("://") {
+        domain = rest;
+    }
+    if let Some(end) = domain.find(['/', '?', '#']) {
+        domain = &domain[..end];
+    }
+    if let Some((_, host)) = domain.rsplit_once('@') {
+        domain = host;
+    }
+    if let Some((host, port)) = domain.rsplit_once(':') {
+        if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) {
+            domain = host;
+        }
+    }
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return Err(String::from("empty domain"));
+    }
+
+    let ascii = idna::domain_to_ascii(domain)
+        .map_err(|_| String::from("not a valid internationalised domain"))?;
+    if !is_valid_domain(&ascii) {
+        return Err(format!("{} is not a valid domain", ascii));
+    }
+    if ascii
+        .split('.')
+        .any(|label| label.starts_with('-') || label.ends_with('-'))
+    {
+        return Err(format!(
+            "{} has a label starting or ending with a hyphen",
+            ascii
+        ));
+    }
+    Ok(ascii)
+}
+
+/// Returns why the given normalised domain is unlikely to be meant as a list entry, if it is:
+/// clients don't query IP addresses, and single label names such as `localhost` are answered
+/// locally rather than allowed or blocked
+pub fn questionable_domain(domain: &str) -> Option<&'static str> {
+    if domain.parse::<Ipv4Addr>().is_ok() {
+        Some("an IP address, which clients don't look up")
+    } else if !domain.contains('.') {
+        Some("a single label name, which is usually a local name rather than a domain")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_reduced_to_their_domain() {
+        assert_eq!(
+            normalise_domain(" https://user@Bücher.Example.:8443/shop?q=1 "),
+            Ok(String::from("xn--bcher-kva.example"))
+        );
+        assert_eq!(
+            normalise_domain("example.com."),
+            Ok(String::from("example.com"))
+        );
+        assert_eq!(
+            normalise_domain("_dmarc.example.com"),
+            Ok(String::from("_dmarc.example.com"))
+        );
+    }
+
+    #[test]
+    fn labels_with_a_leading_or_trailing_hyphen_are_rejected() {
+        for input in ["-bad-.com", "-bad.com", "bad-.com", "example.-com"] {
+            assert!(normalise_domain(input).is_err(), "{}", input);
+        }
+        assert_eq!(
+            normalise_domain("a-b.example.com"),
+            Ok(String::from("a-b.example.com"))
+        );
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        for input in ["", "https://", "...", "a..b", "exa mple.com", "[::1]"] {
+            assert!(normalise_domain(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn ip_addresses_and_single_labels_are_questionable() {
+        assert_eq!(
+            normalise_domain("192.168.1.1"),
+            Ok(String::from("192.168.1.1"))
+        );
+        assert!(questionable_domain("192.168.1.1").is_some());
+        assert!(questionable_domain("localhost").is_some());
+        assert!(questionable_domain("example.com").is_none());
+        assert!(questionable_domain("1.example.com").is_none());
+    }
+
+    #[test]
+    fn host_names_are_stricter_than_domains() {
+        assert!(is_valid_hostname("nas-1.lan"));
+        assert!(!is_valid_hostname("_dmarc.example.com"));
+        assert!(!is_valid_hostname("-nas"));
+        assert!(is_valid_domain("_dmarc.example.com"));
+        assert!(!is_valid_domain(&"a".repeat(64)));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::pihole::domain;
+use crate::pihole::gravity::DomainType;
+use crate::pihole::regexp::FtlRegex;
+use log::debug;
+use rusqlite::{params, Connection};
+use std::error::Error;
+use std::net::IpAddr;
+
+/// A group a client belongs to
+struct Group {
+    id: i64,
+    name: String,
+    enabled: bool,
+}
+
+impl Group {
+    fn describe(&self) -> String {
+        if self.enabled {
+            format!("{} (id {})", self.name, self.id)
+        } else {
+            format!("{} (id {}, disabled)", self.name, self.id)
+        }
+    }
+}
+
+/// A list entry that decides how a query is answered
+struct Rule {
+    description: String,
+    group: String,
+}
+
+/// Explains how a query of the given type from the given client for the given domain would be
+/// answered with the lists in the given gravity database, following FTL's precedence: exact
+/// whitelist, regex whitelist, exact blacklist, regex blacklist, then gravity. Only enabled
+/// entries assigned to an enabled group of the client apply.
+pub fn explain(
+    conn: &Connection,
+    domain_name: &str,
+    client: &str,
+    query_type: &str,
+    has_gravity: bool,
+) -> Result<(), Box<dyn Error>> {
+    let domain_name = domain::normalise_domain(domain_name)?;
+    let groups = client_groups(conn, client)?;
+    let group_ids: Vec<i64> = groups.iter().filter(|g| g.enabled).map(|g| g.id).collect();
+
+    let checks = [
+        (DomainType::Whitelist, "allowed"),
+        (DomainType::WhitelistRegex, "allowed"),
+        (DomainType::Blacklist, "blocked"),
+        (DomainType::BlacklistRegex, "blocked"),
+    ];
+    for (domain_type, verdict) in checks {
+        if let Some(rule) =
+            find_domain_rule(conn, domain_type, &domain_name, query_type, &group_ids)?
+        {
+            println!(
+                "{} ({}) is {} by {} in group {}",
+                domain_name, query_type, verdict, rule.description, rule.group
+            );
+            return Ok(());
+        }
+    }
+
+    if !has_gravity {
+        match adlist_count(conn, &group_ids)? {
+            0 => println!(
+                "{} ({}) is allowed, it is not on any list of the client and no adlists apply to it",
+                domain_name, query_type
+            ),
+            adlist_count => println!(
+                "{} ({}) is not on any list of the client, {} adlists apply to it whose domains are not in the archive, use --database to check them",
+                domain_name, query_type, adlist_count
+            ),
+        }
+        return Ok(());
+    }
+
+    match find_gravity_rule(conn, &domain_name, &group_ids)? {
+        Some(rule) => println!(
+            "{} ({}) is blocked by {} in group {}",
+            domain_name, query_type, rule.description, rule.group
+        ),
+        None => println!(
+            "{} ({}) is allowed, it is not on any list or adlist of the client",
+            domain_name, query_type
+        ),
+    }
+    Ok(())
+}
+
+/// Finds the groups of the client entry the given client matches, exactly by IP, MAC, host
+/// name or interface, or else by the most specific subnet. Clients without an entry are in
+/// the Default group.
+fn client_groups(conn: &Connection, client: &str) -> Result<Vec<Group>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT id, ip FROM client")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut clients: Vec<(i64, String)> = Vec::new();
+    for row in rows {
+        clients.push(row?);
+    }
+
+    let address = client.parse::<IpAddr>().ok();
+    let matched = clients
+        .iter()
+        .find(|(_, entry)| entry.eq_ignore_ascii_case(client))
+        .or_else(|| {
+            let address = address?;
+            clients
+                .iter()
+                .filter_map(|c| subnet_prefix(&c.1, address).map(|prefix| (c, prefix)))
+                .max_by_key(|(_, prefix)| *prefix)
+                .map(|(c, _)| c)
+        });
+
+    let groups = match matched {
+        Some((id, entry)) => {
+            let groups = read_groups(
+                conn,
+                "SELECT g.id, g.name, g.enabled FROM client_by_group c
+                 JOIN \"group\" g ON g.id = c.group_id WHERE c.client_id = ? ORDER BY g.id",
+                *id,
+            )?;
+            println!(
+                "client {} is client entry {} (id {}) in groups: {}",
+                client,
+                entry,
+                id,
+                describe_groups(&groups)
+            );
+            groups
+        }
+        None => {
+            let groups = read_groups(
+                conn,
+                "SELECT id, name, enabled FROM \"group\" WHERE id = ?",
+                0,
+            )?;
+            println!(
+                "client {} has no client entry and is in groups: {}",
+                client,
+                describe_groups(&groups)
+            );
+            groups
+        }
+    };
+    Ok(groups)
+}
+
+fn read_groups(conn: &Connection, sql: &str, id: i64) -> Result<Vec<Group>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params![id], |row| {
+        Ok(Group {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            enabled: row.get(2)?,
+        })
+    })?;
+
+    let mut groups: Vec<Group> = Vec::new();
+    for row in rows {
+        groups.push(row?);
+    }
+    Ok(groups)
+}
+
+fn describe_groups(groups: &[Group]) -> String {
+    if groups.is_empty() {
+        return String::from("none");
+    }
+    groups
+        .iter()
+        .map(Group::describe)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Finds the first enabled entry of the given type matching the domain, assigned to one of
+/// the given groups
+fn find_domain_rule(
+    conn: &Connection,
+    domain_type: DomainType,
+    domain_name: &str,
+    query_type: &str,
+    group_ids: &[i64],
+) -> Result<Option<Rule>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT d.id, d.domain, g.id, g.name FROM domainlist d
+         JOIN domainlist_by_group dg ON dg.domainlist_id = d.id
+         JOIN \"group\" g ON g.id = dg.group_id
+         WHERE d.type = ?1 AND (?1 IN (2, 3) OR d.domain = ?2)
+         AND d.enabled = 1 AND g.enabled = 1 ORDER BY d.id, g.id",
+    )?;
+    let rows = stmt.query_map(params![domain_type as i32, domain_name], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+
+    let mut noted: Option<i64> = None;
+    for row in rows {
+        let (id, entry, group_id, group_name) = row?;
+        if !group_ids.contains(&group_id) {
+            continue;
+        }
+
+        let matches = match domain_type {
+            DomainType::Whitelist | DomainType::Blacklist => {
+                entry.eq_ignore_ascii_case(domain_name)
+            }
+            DomainType::WhitelistRegex | DomainType::BlacklistRegex => {
+                match FtlRegex::parse(&entry) {
+                    Ok(FtlRegex {
+                        unsupported: Some(reason),
+                        ..
+                    }) => {
+                        // entries are listed once per group
+                        if noted != Some(id) {
+                            println!(
+                                "note: {} entry {} (id {}) is not evaluated: {}",
+                                domain_type, entry, id, reason
+                            );
+                            noted = Some(id);
+                        }
+                        false
+                    }
+                    Ok(regex) => regex.matches(domain_name, query_type),
+                    Err(e) => {
+                        debug!("FTL would not compile regex {} (id {}): {}", entry, id, e);
+                        false
+                    }
+                }
+            }
+        };
+        if matches {
+            return Ok(Some(Rule {
+                description: format!("{} entry {} (id {})", domain_type, entry, id),
+                group: format!("{} (id {})", group_name, group_id),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds an enabled adlist assigned to one of the given groups which has the domain
+fn find_gravity_rule(
+    conn: &Connection,
+    domain_name: &str,
+    group_ids: &[i64],
+) -> Result<Option<Rule>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT a.id, a.address, g.id, g.name FROM gravity gr
+         JOIN adlist a ON a.id = gr.adlist_id
+         JOIN adlist_by_group ag ON ag.adlist_id = a.id
+         JOIN \"group\" g ON g.id = ag.group_id
+         WHERE gr.domain = ? AND a.enabled = 1 AND g.enabled = 1 ORDER BY a.id, g.id",
+    )?;
+    let rows = stmt.query_map(params![domain_name], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (id, address, group_id, group_name) = row?;
+        if group_ids.contains(&group_id) {
+            return Ok(Some(Rule {
+                description: format!("adlist {} (id {})", address, id),
+                group: format!("{} (id {})", group_name, group_id),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Number of enabled adlists assigned to any of the given groups
+fn adlist_count(conn: &Connection, group_ids: &[i64]) -> Result<usize, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT a.id, ag.group_id FROM adlist a
+         JOIN adlist_by_group ag ON ag.adlist_id = a.id WHERE a.enabled = 1",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+
+    let mut adlists: Vec<i64> = Vec::new();
+    for row in rows {
+        let (id, group_id) = row?;
+        if group_ids.contains(&group_id) && !adlists.contains(&id) {
+            adlists.push(id);
+        }
+    }
+    Ok(adlists.len())
+}
+
+/// Length of the prefix of the given client entry if it is a subnet containing the address,
+/// ex: `10.0.0.0/24` contains `10.0.0.5` with a prefix of 24
+fn subnet_prefix(entry: &str, address: IpAddr) -> Option<u32> {
+    let (network, prefix) = entry.split_once('/')?;
+    let network = network.parse::<IpAddr>().ok()?;
+    let prefix = prefix.parse::<u32>().ok()?;
+
+    let contains = match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(address) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(address)) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    };
+    contains.then_some(prefix)
+}
@@ -0,0 +1,50 @@
+use log::{debug, warn};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::Path;
+
+/// Reads the given file, treating a missing file as empty
+pub fn read_or_empty(path: &str) -> Result<String, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Replaces the contents of the given file by writing a temporary file next to it and renaming
+/// it over the original, so that readers never see a partially written file. The permissions
+/// and owner of an existing file are kept.
+pub fn write_atomically(path: &str, contents: &str) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.pihole-restore.tmp", path);
+
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(contents.as_bytes())?;
+    tmp_file.sync_all()?;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(&tmp_path, metadata.permissions())?;
+        if let Err(e) = chown(&tmp_path, Some(metadata.uid()), Some(metadata.gid())) {
+            warn!("could not keep the owner of {}: {}", path, e);
+        }
+    }
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Box::new(e));
+    }
+
+    // the rename is only durable once the directory entry is
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    debug!("wrote {} atomically", path);
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::pihole::wildcard;
+use std::collections::BTreeSet;
+
+/// keywords restored when no filters are given, or with `all`
+const DEFAULT_KEYWORDS: [&str; 11] = [
+    "blacklist",
+    "blacklistregex",
+    "whitelist",
+    "whitelistregex",
+    "adlist",
+    "auditlog",
+    "group",
+    "client",
+    "staticdhcp",
+    "localdns",
+    "localcname",
+];
+
+/// keywords of configuration beyond Pihole's lists, which is only restored when asked for
+const OPT_IN_KEYWORDS: [&str; 4] = ["setupvars", "hosts", "ftlconf", "dnsmasq"];
+
+/// keywords of the domainlist sections, whose group assignments share a section
+pub const DOMAINLIST_KEYWORDS: [&str; 4] =
+    ["blacklist", "blacklistregex", "whitelist", "whitelistregex"];
+
+/// names for several keywords at once
+const KEYWORD_GROUPS: [(&str, &[&str]); 4] = [
+    ("all", &DEFAULT_KEYWORDS),
+    (
+        "lists",
+        &[
+            "blacklist",
+            "blacklistregex",
+            "whitelist",
+            "whitelistregex",
+            "adlist",
+        ],
+    ),
+    ("dns", &["localdns", "localcname"]),
+    ("dhcp", &["staticdhcp"]),
+];
+
+/// Filters suggested for a misspelt one are at most this many edits away from it
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// The parts of an archive selected to be restored
+#[derive(Debug)]
+pub struct Filters {
+    keywords: BTreeSet<&'static str>,
+    /// whether the group assignment sections of the selected entries are restored
+    assignments: bool,
+}
+
+impl Filters {
+    /// Resolves the given comma separated filters, removing the excluded ones. Each filter is a
+    /// keyword, a group of keywords such as `lists`, or a pattern with `*` as wildcard, and
+    /// filters prefixed with `!` are removed from the ones before them, ex: `all,!auditlog`.
+    pub fn parse(filters: &str, exclude: &str, assignments: bool) -> Result<Filters, String> {
+        let mut keywords: BTreeSet<&'static str> = BTreeSet::new();
+        for filter in wildcard::split_list(filters) {
+            match filter.strip_prefix('!') {
+                Some(negated) => {
+                    for keyword in resolve(negated)? {
+                        keywords.remove(keyword);
+                    }
+                }
+                None => keywords.extend(resolve(&filter)?),
+            }
+        }
+        for filter in wildcard::split_list(exclude) {
+            for keyword in resolve(&filter)? {
+                keywords.remove(keyword);
+            }
+        }
+
+        if keywords.is_empty() {
+            return Err(String::from("the filters don't leave anything to restore"));
+        }
+        Ok(Filters {
+            keywords,
+            assignments,
+        })
+    }
+
+    pub fn contains(&self, keyword: &str) -> bool {
+        self.keywords.contains(keyword)
+    }
+
+    pub fn contains_any(&self, keywords: &[&str]) -> bool {
+        keywords.iter().any(|keyword| self.contains(keyword))
+    }
+
+    /// Whether the `*_by_group` sections of the selected entries are restored
+    pub fn assignments(&self) -> bool {
+        self.assignments
+    }
+
+    /// The selected keywords, in alphabetical order
+    pub fn keywords(&self) -> Vec<&str> {
+        self.keywords.iter().copied().collect()
+    }
+}
+
+/// Resolves a single filter into the keywords it selects
+fn resolve(filter: &str) -> Result<Vec<&'static str>, String> {
+    let filter = filter.trim().to_lowercase();
+    if let Some((_, keywords)) = KEYWORD_GROUPS.iter().find(|(name, _)| *name == filter) {
+        return Ok(keywords.to_vec());
+    }
+
+    // opt-in keywords have to be named, so that a pattern never rewrites the host configuration
+    if filter.contains(['*', '?']) {
+        let matched: Vec<&'static str> = DEFAULT_KEYWORDS
+            .iter()
+            .filter(|keyword| wildcard::matches(&filter, keyword))
+            .copied()
+            .collect();
+        if matched.is_empty() {
+            let opt_in: Vec<&str> = OPT_IN_KEYWORDS
+                .iter()
+                .filter(|keyword| wildcard::matches(&filter, keyword))
+                .copied()
+                .collect();
+            if !opt_in.is_empty() {
+                return Err(format!(
+                    "filter {} only matches opt-in keywords, which have to be named: {}",
+                    filter,
+                    opt_in.join(", ")
+                ));
+            }
+            return Err(format!("filter {} doesn't match any keyword", filter));
+        }
+        return Ok(matched);
+    }
+
+    let all_keywords = DEFAULT_KEYWORDS.iter().chain(OPT_IN_KEYWORDS.iter());
+    if let Some(keyword) = all_keywords.clone().find(|keyword| **keyword == filter) {
+        return Ok(vec![keyword]);
+    }
+
+    let names: Vec<&str> = all_keywords
+        .copied()
+        .chain(KEYWORD_GROUPS.iter().map(|(name, _)| *name))
+        .collect();
+    let suggestion = names
+        .iter()
+        .map(|name| (strsim::levenshtein(&filter, name), *name))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min();
+    match suggestion {
+        Some((_, name)) => Err(format!(
+            "invalid filter found: {}, did you mean {}?",
+            filter, name
+        )),
+        None => Err(format!(
+            "invalid filter found: {}, expected one of: {}",
+            filter,
+            names.join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negated_filters_are_removed_from_the_ones_before_them() {
+        let filters = Filters::parse("all,!auditlog,!dhcp", "", true).unwrap();
+        assert!(filters.contains("blacklist"));
+        assert!(filters.contains("localdns"));
+        assert!(!filters.contains("auditlog"));
+        assert!(!filters.contains("staticdhcp"));
+        assert_eq!(filters.keywords().len(), DEFAULT_KEYWORDS.len() - 2);
+
+        // a filter after a negation adds its keywords back
+        let filters = Filters::parse("!auditlog,all", "", true).unwrap();
+        assert!(filters.contains("auditlog"));
+    }
+
+    #[test]
+    fn excluded_filters_are_removed_last() {
+        let filters = Filters::parse("lists,dns", "localcname,*regex", true).unwrap();
+        assert_eq!(
+            filters.keywords(),
+            vec!["adlist", "blacklist", "localdns", "whitelist"]
+        );
+    }
+
+    #[test]
+    fn patterns_match_default_keywords_only() {
+        let filters = Filters::parse("*regex", "", true).unwrap();
+        assert_eq!(filters.keywords(), vec!["blacklistregex", "whitelistregex"]);
+
+        let filters = Filters::parse("*", "", true).unwrap();
+        assert!(!filters.contains_any(&OPT_IN_KEYWORDS));
+
+        let filters = Filters::parse("*s", "", true).unwrap();
+        assert_eq!(filters.keywords(), vec!["localdns"]);
+
+        assert!(Filters::parse("*conf", "", true).is_err());
+        assert!(Filters::parse("*xyz", "", true).is_err());
+    }
+
+    #[test]
+    fn opt_in_keywords_are_restored_when_named() {
+        let filters = Filters::parse("all,setupvars,HOSTS", "", true).unwrap();
+        assert!(filters.contains("setupvars"));
+        assert!(filters.contains("hosts"));
+        assert!(!filters.contains("ftlconf"));
+    }
+
+    #[test]
+    fn misspelt_filters_are_suggested_a_keyword() {
+        let e = Filters::parse("blacklst", "", true).unwrap_err();
+        assert!(e.contains("did you mean blacklist?"), "{}", e);
+
+        let e = Filters::parse("all", "dchp", true).unwrap_err();
+        assert!(e.contains("did you mean dhcp?"), "{}", e);
+
+        let e = Filters::parse("nothinglikeit", "", true).unwrap_err();
+        assert!(e.contains("expected one of"), "{}", e);
+    }
+
+    #[test]
+    fn filters_leaving_nothing_are_rejected() {
+        assert!(Filters::parse("all", "all", true).is_err());
+        assert!(Filters::parse("dns,!localdns,!localcname", "", true).is_err());
+        assert!(Filters::parse("", "", true).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::pihole::conf::{self, ConfFile, KeyPolicy};
+use crate::pihole::files;
+use crate::pihole::wildcard;
+use flate2::read::GzDecoder;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const FTL_CONF_FILE: &str = "/etc/pihole/pihole-FTL.conf";
+
+/// keys taken from the archive unless specified otherwise
+pub const DEFAULT_ALLOWED_KEYS: &str = "PRIVACYLEVEL,RATE_LIMIT,BLOCKINGMODE,BLOCK_TTL,BLOCK_ESNI,BLOCK_ICLOUD_PR,MOZILLA_CANARY,CNAME_DEEP_INSPECT,AAAA_QUERY_ANALYSIS,ANALYZE_ONLY_A_AND_AAAA,IGNORE_LOCALHOST,SHOW_DNSSEC,EDNS0_ECS,REPLY_WHEN_BUSY,PIHOLE_PTR,RESOLVE_IPV4,RESOLVE_IPV6,REFRESH_HOSTNAMES,NAMES_FROM_NETDB,MAXDBDAYS,DBINTERVAL,MAXNETAGE,MAXLOGAGE,DBIMPORT";
+
+/// keys that bind FTL to this host (interfaces, addresses, ports, file locations), which are
+/// never taken from the archive unless specified otherwise
+pub const DEFAULT_DENIED_KEYS: &str = "SOCKET_LISTENING,FTLPORT,LOCAL_IPV4,LOCAL_IPV6,BLOCK_IPV4,BLOCK_IPV6,REPLY_ADDR4,REPLY_ADDR6,*FILE,*DB,DEBUG_*";
+
+/// Type of the value of a pihole-FTL.conf key, as FTL parses it
+#[derive(Debug, Clone, Copy)]
+enum KeyType {
+    /// `true`/`false`, FTL also accepts `yes`/`no` for some of the older keys
+    Bool,
+    Integer {
+        min: i64,
+        max: i64,
+    },
+    Decimal,
+    Choice(&'static [&'static str]),
+    Ipv4,
+    Ipv6,
+    /// `<queries>/<seconds>`, ex: `1000/60`
+    RateLimit,
+    Path,
+}
+
+/// Keys of pihole-FTL.conf and their value types, see
+/// https://docs.pi-hole.net/ftldns/configfile/
+const KEY_TYPES: &[(&str, KeyType)] = &[
+    ("SOCKET_LISTENING", KeyType::Choice(&["localonly", "all"])),
+    ("QUERY_DISPLAY", KeyType::Bool),
+    ("AAAA_QUERY_ANALYSIS", KeyType::Bool),
+    ("ANALYZE_ONLY_A_AND_AAAA", KeyType::Bool),
+    ("RESOLVE_IPV6", KeyType::Bool),
+    ("RESOLVE_IPV4", KeyType::Bool),
+    ("IGNORE_LOCALHOST", KeyType::Bool),
+    ("SHOW_DNSSEC", KeyType::Bool),
+    ("CNAME_DEEP_INSPECT", KeyType::Bool),
+    ("BLOCK_ESNI", KeyType::Bool),
+    ("BLOCK_ICLOUD_PR", KeyType::Bool),
+    ("MOZILLA_CANARY", KeyType::Bool),
+    ("EDNS0_ECS", KeyType::Bool),
+    ("NAMES_FROM_NETDB", KeyType::Bool),
+    ("PARSE_ARP_CACHE", KeyType::Bool),
+    ("DBIMPORT", KeyType::Bool),
+    ("CHECK_LOAD", KeyType::Bool),
+    ("ADDR2LINE", KeyType::Bool),
+    ("PRIVACYLEVEL", KeyType::Integer { min: 0, max: 3 }),
+    ("FTLPORT", KeyType::Integer { min: 1, max: 65535 }),
+    (
+        "BLOCK_TTL",
+        KeyType::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+    ),
+    (
+        "MAXDBDAYS",
+        KeyType::Integer {
+            min: -1,
+            max: 24855,
+        },
+    ),
+    ("MAXNETAGE", KeyType::Integer { min: 0, max: 8925 }),
+    ("DELAY_STARTUP", KeyType::Integer { min: 0, max: 300 }),
+    ("NICE", KeyType::Integer { min: -20, max: 19 }),
+    ("CHECK_SHMEM", KeyType::Integer { min: 0, max: 100 }),
+    ("CHECK_DISK", KeyType::Integer { min: 0, max: 100 }),
+    ("DBINTERVAL", KeyType::Decimal),
+    ("MAXLOGAGE", KeyType::Decimal),
+    (
+        "BLOCKINGMODE",
+        KeyType::Choice(&["NULL", "IP-NODATA-AAAA", "IP", "NXDOMAIN", "NODATA"]),
+    ),
+    (
+        "REPLY_WHEN_BUSY",
+        KeyType::Choice(&["DROP", "ALLOW", "BLOCK", "REFUSE"]),
+    ),
+    (
+        "PIHOLE_PTR",
+        KeyType::Choice(&["PI.HOLE", "HOSTNAME", "HOSTNAMEFQDN", "NONE"]),
+    ),
+    (
+        "REFRESH_HOSTNAMES",
+        KeyType::Choice(&["IPV4", "IPV4_ONLY", "ALL", "UNKNOWN", "NONE"]),
+    ),
+    ("RATE_LIMIT", KeyType::RateLimit),
+    ("LOCAL_IPV4", KeyType::Ipv4),
+    ("BLOCK_IPV4", KeyType::Ipv4),
+    ("REPLY_ADDR4", KeyType::Ipv4),
+    ("LOCAL_IPV6", KeyType::Ipv6),
+    ("BLOCK_IPV6", KeyType::Ipv6),
+    ("REPLY_ADDR6", KeyType::Ipv6),
+    ("DBFILE", KeyType::Path),
+    ("GRAVITYDB", KeyType::Path),
+    ("MACVENDORDB", KeyType::Path),
+    ("LOGFILE", KeyType::Path),
+    ("PIDFILE", KeyType::Path),
+    ("PORTFILE", KeyType::Path),
+    ("SOCKETFILE", KeyType::Path),
+    ("SETUPVARSFILE", KeyType::Path),
+    ("DEBUG_*", KeyType::Bool),
+];
+
+/// Merges the keys permitted by the given policy from the archived pihole-FTL.conf into the
+/// one of this Pihole setup. Values that FTL would not accept are left out. Returns the number
+/// of keys that changed, any of which only take effect after pihole-FTL is restarted.
+pub fn process_ftl_conf(
+    file: &mut tar::Entry<'_, GzDecoder<File>>,
+    policy: &KeyPolicy,
+) -> Result<i32, Box<dyn Error>> {
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let mut incoming = ConfFile::parse(&s);
+
+    incoming.retain(|key, value| {
+        if !policy.permits(key) {
+            debug!(
+                "not restoring pihole-FTL.conf key {} as per key policy",
+                key
+            );
+            return false;
+        }
+        match validate(key, value) {
+            Ok(_) => true,
+            Err(reason) => {
+                warn!(
+                    "not restoring pihole-FTL.conf key {}={}: {}",
+                    key, value, reason
+                );
+                false
+            }
+        }
+    });
+
+    let mut current = ConfFile::parse(&files::read_or_empty(FTL_CONF_FILE)?);
+    let changes = current.merge(&incoming, policy);
+
+    if changes.is_empty() {
+        info!("pihole-FTL.conf is already up to date");
+        return Ok(0);
+    }
+
+    conf::log_changes("pihole-FTL.conf", &changes);
+    files::write_atomically(FTL_CONF_FILE, &current.render())?;
+
+    Ok(changes.len() as i32)
+}
+
+/// Checks the given value against the type of the given key, returning the reason if FTL
+/// would reject it. Keys unknown here are taken as they are.
+fn validate(key: &str, value: &str) -> Result<(), String> {
+    let key_type = match KEY_TYPES
+        .iter()
+        .find(|(pattern, _)| wildcard::matches(pattern, key))
+    {
+        Some((_, key_type)) => *key_type,
+        None => {
+            debug!(
+                "unknown pihole-FTL.conf key {}, not validating its value",
+                key
+            );
+            return Ok(());
+        }
+    };
+
+    match key_type {
+        KeyType::Bool => match value.to_lowercase().as_str() {
+            "true" | "false" | "yes" | "no" => Ok(()),
+            _ => Err(String::from("expected true or false")),
+        },
+        KeyType::Integer { min, max } => match value.parse::<i64>() {
+            Ok(n) if n >= min && n <= max => Ok(()),
+            Ok(_) => Err(format!("expected a number from {} to {}", min, max)),
+            Err(_) => Err(String::from("expected a number")),
+        },
+        KeyType::Decimal => match value.parse::<f64>() {
+            Ok(n) if n >= 0.0 => Ok(()),
+            _ => Err(String::from("expected a positive number")),
+        },
+        KeyType::Choice(choices) => {
+            if choices.iter().any(|c| c.eq_ignore_ascii_case(value)) {
+                Ok(())
+            } else {
+                Err(format!("expected one of {}", choices.join(", ")))
+            }
+        }
+        KeyType::Ipv4 => value
+            .parse::<Ipv4Addr>()
+            .map(|_| ())
+            .map_err(|_| String::from("expected an IPv4 address")),
+        KeyType::Ipv6 => value
+            .parse::<Ipv6Addr>()
+            .map(|_| ())
+            .map_err(|_| String::from("expected an IPv6 address")),
+        KeyType::RateLimit => match value.split_once('/') {
+            Some((count, interval))
+                if count.parse::<u32>().is_ok() && interval.parse::<u32>().is_ok() =>
+            {
+                Ok(())
+            }
+            _ => Err(String::from("expected <queries>/<seconds>")),
+        },
+        KeyType::Path => {
+            if value.starts_with('/') {
+                Ok(())
+            } else {
+                Err(String::from("expected an absolute path"))
+            }
+        }
+    }
+}
@@ -1,10 +1,33 @@
+use crate::pihole::domain;
+use crate::pihole::regexp::FtlRegex;
+use crate::pihole::schema;
+use crate::pihole::scope::{GroupOptions, GroupScope, DEFAULT_GROUP_ID};
 use flate2::read::GzDecoder;
-use log::{debug, warn};
-use rusqlite::{named_params, params, Connection};
-use serde::Deserialize;
+use log::{debug, info, warn};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension, ToSql};
+use serde::de::{self, DeserializeOwned, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::marker::PhantomData;
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// number of records inserted per prepared statement batch when none is specified
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+/// milliseconds SQLite waits on a lock before a statement fails when none is specified
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+/// retries of a locked statement after the busy timeout when none is specified
+pub const DEFAULT_LOCK_RETRIES: u32 = 5;
+
+const LOCK_RETRY_INITIAL_BACKOFF_MS: u64 = 200;
+const LOCK_RETRY_MAX_BACKOFF_MS: u64 = 5000;
 
 #[derive(Debug, Clone, Copy)]
 pub enum DomainType {
@@ -14,119 +37,342 @@ pub enum DomainType {
     BlacklistRegex = 3,
 }
 
+impl DomainType {
+    /// The domain type stored in the `domainlist.type` column
+    pub fn from_id(id: i32) -> Option<DomainType> {
+        match id {
+            0 => Some(DomainType::Whitelist),
+            1 => Some(DomainType::Blacklist),
+            2 => Some(DomainType::WhitelistRegex),
+            3 => Some(DomainType::BlacklistRegex),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DomainType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DomainType::Whitelist => "whitelist (exact)",
+            DomainType::Blacklist => "blacklist (exact)",
+            DomainType::WhitelistRegex => "whitelist (regex)",
+            DomainType::BlacklistRegex => "blacklist (regex)",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Connection settings for the gravity database being restored into
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub file: String,
+    /// number of records read from the archive and inserted at a time
+    pub batch_size: usize,
+    /// trade durability for speed while restoring, see `tune_for_bulk_insert`
+    pub bulk: bool,
+    /// how long SQLite waits on a lock held by another process before giving up
+    pub busy_timeout: Duration,
+    /// how many times a locked statement is retried after the busy timeout is exhausted
+    pub lock_retries: u32,
+}
+
 pub fn restore_domainlist(
-    db_file: &str,
+    db: &DbConfig,
     domain_type: DomainType,
     file: &mut tar::Entry<'_, GzDecoder<File>>,
     flush: bool,
+    groups: &GroupOptions,
 ) -> Result<i32, Box<dyn Error>> {
-    let _ = flush
-        && flush_table(
-            db_file,
-            "domainlist",
-            format!("WHERE type = {}", domain_type as i32).as_str(),
-        )?;
-
-    let conn: Connection = connect_sqlite(db_file)?;
-
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
+    let conn: Connection = connect_sqlite(db)?;
+    restore_domainlist_into(&conn, db, domain_type, file, flush, groups)
+}
 
-    let records: Vec<Domain> = serde_json::from_str(&s).unwrap();
-    let record_list: DomainList = DomainList {
-        list: records,
-        domain_type: domain_type as i32,
-    };
+/// Restores the given domainlist section into an open connection, ex: an in-memory database
+pub fn restore_domainlist_into<R: Read>(
+    conn: &Connection,
+    db: &DbConfig,
+    domain_type: DomainType,
+    file: R,
+    flush: bool,
+    groups: &GroupOptions,
+) -> Result<i32, Box<dyn Error>> {
+    // flushing and loading happens in a single transaction, so that a failed restore doesn't
+    // leave an empty list behind
+    in_transaction(conn, db, |conn| {
+        let _ = flush
+            && flush_table(
+                conn,
+                "domainlist",
+                format!("WHERE type = {}", domain_type as i32).as_str(),
+            )?;
+
+        let record_list: DomainList = DomainList {
+            domain_type: domain_type as i32,
+        };
 
-    record_list.restore_table(conn)
+        restore_records(conn, db, &record_list, file, groups)
+    })
 }
 
 pub fn load_table(
-    db_file: &str,
+    db: &DbConfig,
     table: &str,
     file: &mut tar::Entry<'_, GzDecoder<File>>,
     flush: bool,
+    groups: &GroupOptions,
 ) -> Result<i32, Box<dyn Error>> {
-    let conn: Connection = connect_sqlite(db_file)?;
-
-    let mut s = String::new();
-    file.read_to_string(&mut s).unwrap();
+    let conn: Connection = connect_sqlite(db)?;
+    load_table_into(&conn, db, table, file, flush, groups)
+}
 
-    match table {
+/// Loads the given table section into an open connection, ex: an in-memory database
+pub fn load_table_into<R: Read>(
+    conn: &Connection,
+    db: &DbConfig,
+    table: &str,
+    file: R,
+    flush: bool,
+    groups: &GroupOptions,
+) -> Result<i32, Box<dyn Error>> {
+    in_transaction(conn, db, |conn| match table {
         "adlist" => {
             debug!("processing adlist table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<Ad> = serde_json::from_str(&s).unwrap();
-            let record_list: AdList = AdList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &AdList, file, groups)
         }
         "domain_audit" => {
             debug!("processing domain_audit table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<DomainAuditEntry> = serde_json::from_str(&s).unwrap();
-            let record_list: DomainAuditList = DomainAuditList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &DomainAuditList, file, groups)
         }
         "group" => {
             debug!("processing group table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<Group> = serde_json::from_str(&s).unwrap();
-            let record_list: GroupList = GroupList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &GroupList, file, groups)
         }
         "client" => {
             debug!("processing client table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<Client> = serde_json::from_str(&s).unwrap();
-            let record_list: ClientList = ClientList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &ClientList, file, groups)
         }
         "client_by_group" => {
             debug!("processing client_by_group table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<ClientGroupAssignment> = serde_json::from_str(&s).unwrap();
-            let record_list: ClientGroupAssignmentList =
-                ClientGroupAssignmentList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &ClientGroupAssignmentList, file, groups)
         }
         "domainlist_by_group" => {
             debug!("processing domainlist_by_group table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<DomainListGroupAssignment> = serde_json::from_str(&s).unwrap();
-            let record_list: DomainListGroupAssignmentList =
-                DomainListGroupAssignmentList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &DomainListGroupAssignmentList, file, groups)
         }
         "adlist_by_group" => {
             debug!("processing adlist_by_group table");
-            let _ = flush && flush_table(db_file, table, "")?;
+            let _ = flush && flush_table(conn, table, "")?;
 
-            let records: Vec<AdListGroupAssignment> = serde_json::from_str(&s).unwrap();
-            let record_list: AdListGroupAssignmentList =
-                AdListGroupAssignmentList { list: records };
-            Ok(record_list.restore_table(conn)?)
+            restore_records(conn, db, &AdListGroupAssignmentList, file, groups)
         }
         _ => Err(Box::<dyn Error>::from(format!(
             "invalid table name provided: {}",
             table
         ))),
+    })
+}
+
+/// Finds the group with the given name in the gravity db, creating it if it doesn't exist.
+/// Returns the id of the group.
+pub fn ensure_group(db: &DbConfig, name: &str) -> Result<i32, Box<dyn Error>> {
+    let conn: Connection = connect_sqlite(db)?;
+    let existing = conn.query_row(
+        "SELECT id FROM \"group\" WHERE name = ?",
+        params![name],
+        |row| row.get::<_, i32>(0),
+    );
+    match existing {
+        Ok(id) => {
+            info!(
+                "assigning restored entries to existing group {} (id {})",
+                name, id
+            );
+            Ok(id)
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            with_lock_retry(db, || {
+                conn.execute("INSERT INTO \"group\" (name) VALUES (?)", params![name])
+            })?;
+            let id = conn.last_insert_rowid() as i32;
+            info!(
+                "assigning restored entries to new group {} (id {})",
+                name, id
+            );
+            Ok(id)
+        }
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Reads the schema version of the given gravity database
+pub fn target_schema_version(db_file: &str) -> Result<Option<i32>, Box<dyn Error>> {
+    let conn = open_read_only(db_file)?;
+    schema::read_version(&conn)
+}
+
+/// Opens the given gravity database for reading only, ex: to look up entries on this setup
+pub fn open_read_only(db_file: &str) -> Result<Connection, Box<dyn Error>> {
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(Duration::from_millis(DEFAULT_BUSY_TIMEOUT_MS))?;
+    Ok(conn)
+}
+
+/// Creates a consistent copy of the given gravity database next to it, to be restored into
+/// and swapped in with `swap_in_staging_copy` once the restore is complete. Returns the path
+/// of the copy.
+pub fn create_staging_copy(db: &DbConfig) -> Result<String, Box<dyn Error>> {
+    let db_file = db.file.as_str();
+    let staging_file = format!("{}.staging", db_file);
+    if Path::new(&staging_file).exists() {
+        debug!("removing stale staging copy {}", staging_file);
+        fs::remove_file(&staging_file)?;
+    }
+
+    // VACUUM INTO takes a transactionally consistent snapshot, even while FTL has the db open
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(db.busy_timeout)?;
+    with_lock_retry(db, || conn.execute("VACUUM INTO ?", params![staging_file]))?;
+
+    let metadata = fs::metadata(db_file)?;
+    fs::set_permissions(&staging_file, metadata.permissions())?;
+    if let Err(e) = chown(&staging_file, Some(metadata.uid()), Some(metadata.gid())) {
+        warn!(
+            "could not set the owner of the staging copy {}: {}",
+            staging_file, e
+        );
     }
+
+    debug!("created staging copy {} of {}", staging_file, db_file);
+    Ok(staging_file)
+}
+
+/// Removes the staging copy of a restore that failed, leaving the gravity database as it was
+pub fn discard_staging_copy(staging_file: &str) -> Result<(), Box<dyn Error>> {
+    fs::remove_file(staging_file)?;
+    debug!("removed staging copy {}", staging_file);
+    Ok(())
+}
+
+/// Atomically replaces the gravity database with the restored staging copy
+pub fn swap_in_staging_copy(staging_file: &str, db_file: &str) -> Result<(), Box<dyn Error>> {
+    // a leftover write-ahead log of the old database would be replayed onto the new file
+    let wal_file = format!("{}-wal", db_file);
+    if fs::metadata(&wal_file)
+        .map(|m| m.len() > 0)
+        .unwrap_or(false)
+    {
+        return Err(Box::<dyn Error>::from(format!(
+            "not replacing {} since it has an active write-ahead log, the restored copy is left at {}",
+            db_file, staging_file
+        )));
+    }
+
+    fs::rename(staging_file, db_file)?;
+    debug!("replaced {} with staging copy {}", db_file, staging_file);
+    Ok(())
 }
 
-fn connect_sqlite(db_file: &str) -> Result<Connection, Box<dyn Error>> {
-    debug!("connecting to SQLite db: {}", db_file);
-    let connection = Connection::open(db_file)?;
+fn connect_sqlite(db: &DbConfig) -> Result<Connection, Box<dyn Error>> {
+    debug!("connecting to SQLite db: {}", db.file);
+    // never create the file here, an empty database would fail every insert with "no such table"
+    let connection = Connection::open_with_flags(
+        &db.file,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    connection.busy_timeout(db.busy_timeout)?;
+    if db.bulk {
+        tune_for_bulk_insert(&connection)?;
+    }
     Ok(connection)
 }
 
-fn flush_table(db_file: &str, table: &str, condition: &str) -> Result<bool, Box<dyn Error>> {
-    let conn: Connection = connect_sqlite(db_file)?;
+/// Relaxes the durability settings of the given connection for the duration of the restore.
+/// Both settings only apply to this connection, so they are reverted when it is closed.
+fn tune_for_bulk_insert(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    // don't wait for every write to reach the disk, which is what makes SD cards slow
+    conn.pragma_update(None, "synchronous", "OFF")?;
+
+    // WAL mode is persisted in the db file, so that is left alone
+    let journal_mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        let _: String =
+            conn.pragma_update_and_check(None, "journal_mode", "MEMORY", |row| row.get(0))?;
+    }
+
+    debug!("tuned connection for bulk inserts");
+    Ok(())
+}
+
+/// Runs the given restore in an immediate transaction, which takes the write lock up front so
+/// that pihole-FTL or a gravity update can't lock the database out half way through a section.
+/// Starting and committing the transaction is retried while the database is locked.
+fn in_transaction<F>(conn: &Connection, db: &DbConfig, restore: F) -> Result<i32, Box<dyn Error>>
+where
+    F: FnOnce(&Connection) -> Result<i32, Box<dyn Error>>,
+{
+    with_lock_retry(db, || conn.execute_batch("BEGIN IMMEDIATE"))?;
+
+    let result = restore(conn).and_then(|processed_count| {
+        with_lock_retry(db, || conn.execute_batch("COMMIT"))?;
+        Ok(processed_count)
+    });
+
+    if result.is_err() {
+        if let Err(e) = conn.execute_batch("ROLLBACK") {
+            warn!("error while rolling back the failed restore: {}", e);
+        }
+    }
+
+    result
+}
+
+/// Runs the given statement, retrying with an exponential backoff for as long as it fails
+/// because the database is busy or locked, up to the configured number of retries
+fn with_lock_retry<T, F>(db: &DbConfig, mut statement: F) -> rusqlite::Result<T>
+where
+    F: FnMut() -> rusqlite::Result<T>,
+{
+    let mut backoff = Duration::from_millis(LOCK_RETRY_INITIAL_BACKOFF_MS);
+    let mut attempt = 0;
+    loop {
+        match statement() {
+            Err(e) if is_locked(&e) && attempt < db.lock_retries => {
+                attempt += 1;
+                warn!(
+                    "gravity db is locked, retrying in {}ms ({}/{})",
+                    backoff.as_millis(),
+                    attempt,
+                    db.lock_retries
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_millis(LOCK_RETRY_MAX_BACKOFF_MS));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_locked(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.code == ErrorCode::DatabaseBusy || failure.code == ErrorCode::DatabaseLocked
+    )
+}
+
+fn flush_table(conn: &Connection, table: &str, condition: &str) -> Result<bool, Box<dyn Error>> {
     let table_exists_sql = "SELECT name FROM sqlite_master WHERE type='table' AND name=?";
     let mut table_entry_stmt = conn.prepare(table_exists_sql)?;
     let mut table_entry = table_entry_stmt.query(params![table])?;
@@ -152,14 +398,384 @@ fn flush_table(db_file: &str, table: &str, condition: &str) -> Result<bool, Box<
     }
 }
 
+/// Streams the records of the given JSON array section into the table handled by the given
+/// Restorable, so that at most `batch_size` records are held in memory at any given time.
+/// Only the columns that exist in the target table are inserted, see `ColumnMapping`. With a
+/// group scope, only the records belonging to its groups are restored, and with a target group
+/// the inserted records are assigned to it. Returns the number of records inserted, records the
+/// target already has are reported separately.
+fn restore_records<T: Restorable, R: Read>(
+    conn: &Connection,
+    db: &DbConfig,
+    table: &T,
+    file: R,
+    groups: &GroupOptions,
+) -> Result<i32, Box<dyn Error>> {
+    let table_name = table.table();
+    debug!("restoring {} table", table_name);
+
+    let target_columns = schema::table_columns(conn, table_name)?;
+    if target_columns.is_empty() {
+        return Err(Box::<dyn Error>::from(format!(
+            "table doesn't exist in the target gravity db: {}",
+            table_name
+        )));
+    }
+
+    let mut mapping = ColumnMapping::default();
+    let (mut normalised_count, mut rejected_count, mut out_of_scope_count) = (0, 0, 0);
+    // entries the target already has are ignored by the insert, and only assigned to the
+    // target group
+    let (mut existing_count, mut assigned_count, mut unassigned_count) = (0, 0, 0);
+    let scope = groups.scope.as_ref();
+    // Pihole links every new entry to the Default group, which is undone unless it is restored
+    let unlink_default = groups.unlinks_default();
+    let inserted_count = stream_records(file, db.batch_size, |batch: Vec<Map<String, Value>>| {
+        debug!(
+            "starting to load batch of {} records to {}",
+            batch.len(),
+            table_name
+        );
+
+        let mut inserted_count = 0;
+        for row in batch {
+            mapping.archive_fields.extend(row.keys().cloned());
+
+            let mut record: T::Record = match serde_json::from_value(Value::Object(row)) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("invalid entry found in the {} section: {}", table_name, e);
+                    rejected_count += 1;
+                    continue;
+                }
+            };
+
+            if let Some(scope) = scope {
+                if !table.in_scope(&record, scope) {
+                    out_of_scope_count += 1;
+                    continue;
+                }
+            }
+
+            match table.normalise(&mut record) {
+                Ok(true) => normalised_count += 1,
+                Ok(false) => {}
+                Err(reason) => {
+                    warn!("not restoring an entry to {} table: {}", table_name, reason);
+                    rejected_count += 1;
+                    continue;
+                }
+            }
+
+            if let Err(reason) = table.check(&record, &target_columns) {
+                warn!("not restoring an entry to {} table: {}", table_name, reason);
+                rejected_count += 1;
+                continue;
+            }
+
+            // fields the target schema doesn't have are left out of the insert
+            let values: Vec<(&str, &dyn ToSql)> = table
+                .values(&record)
+                .into_iter()
+                .filter(|(column, _)| target_columns.contains(*column))
+                .collect();
+            mapping
+                .bound_columns
+                .extend(values.iter().map(|(column, _)| column.to_string()));
+
+            let sql = format!(
+                "INSERT OR IGNORE INTO \"{}\" ({}) VALUES ({});",
+                table_name,
+                values
+                    .iter()
+                    .map(|(column, _)| *column)
+                    .collect::<Vec<&str>>()
+                    .join(","),
+                vec!["?"; values.len()].join(",")
+            );
+            let mut stmt = conn.prepare_cached(&sql)?;
+            let params: Vec<&dyn ToSql> = values.iter().map(|(_, value)| *value).collect();
+
+            match with_lock_retry(db, || stmt.execute(params.as_slice())) {
+                Ok(0) => {
+                    existing_count += 1;
+                    let link = table.default_group_link(&record);
+                    if let (Some(_), Some((link_table, column, _))) = (groups.target_group_id, link)
+                    {
+                        match with_lock_retry(db, || table.existing_id(conn, &record))? {
+                            Some(id) => {
+                                relink_groups(conn, db, groups, link_table, column, id, false)?;
+                                assigned_count += 1;
+                            }
+                            None => unassigned_count += 1,
+                        }
+                    }
+                }
+                Ok(_) => {
+                    inserted_count += 1;
+                    if let Some((link_table, column, id)) = table.default_group_link(&record) {
+                        relink_groups(conn, db, groups, link_table, column, id, unlink_default)?;
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "error while inserting an entry to {} table: {}",
+                        table_name, e
+                    );
+                }
+            }
+        }
+
+        Ok(inserted_count)
+    })?;
+
+    mapping.report(table_name, &target_columns, schema::read_version(conn)?);
+    if normalised_count > 0 || rejected_count > 0 {
+        warn!(
+            "{}: {} entries normalised, {} entries rejected",
+            table_name, normalised_count, rejected_count
+        );
+    }
+    if out_of_scope_count > 0 {
+        info!(
+            "{}: {} entries of other groups left out",
+            table_name, out_of_scope_count
+        );
+    }
+    if existing_count > 0 {
+        info!(
+            "{}: {} entries already on the target left as they were",
+            table_name, existing_count
+        );
+    }
+    if assigned_count > 0 {
+        info!(
+            "{}: {} entries already on the target assigned to the target group",
+            table_name, assigned_count
+        );
+    }
+    if unassigned_count > 0 {
+        warn!(
+            "{}: {} entries whose id is taken by another entry on the target were not assigned to the target group",
+            table_name, unassigned_count
+        );
+    }
+    Ok(inserted_count)
+}
+
+/// Assigns a newly inserted record to the target group, if any, and removes the Default group
+/// link Pihole's insert trigger added when it isn't wanted
+fn relink_groups(
+    conn: &Connection,
+    db: &DbConfig,
+    groups: &GroupOptions,
+    link_table: &str,
+    column: &str,
+    id: i32,
+    unlink_default: bool,
+) -> Result<(), Box<dyn Error>> {
+    if unlink_default {
+        let sql = format!(
+            "DELETE FROM \"{}\" WHERE {} = ? AND group_id = ?;",
+            link_table, column
+        );
+        with_lock_retry(db, || conn.execute(&sql, params![id, DEFAULT_GROUP_ID]))?;
+    }
+    if let Some(group_id) = groups.target_group_id {
+        let sql = format!(
+            "INSERT OR IGNORE INTO \"{}\" ({}, group_id) VALUES (?, ?);",
+            link_table, column
+        );
+        with_lock_retry(db, || conn.execute(&sql, params![id, group_id]))?;
+    }
+    Ok(())
+}
+
+/// Keeps track of how the fields of the archive section map onto the columns of the target
+/// table, which differ between Pi-hole releases
+#[derive(Debug, Default)]
+struct ColumnMapping {
+    /// fields found in the archive section
+    archive_fields: BTreeSet<String>,
+    /// columns which were given a value from the archive
+    bound_columns: BTreeSet<String>,
+}
+
+impl ColumnMapping {
+    fn report(&self, table: &str, target_columns: &HashSet<String>, target_version: Option<i32>) {
+        match (
+            schema::implied_version(table, &self.archive_fields),
+            target_version,
+        ) {
+            (Some(archive_version), Some(target_version)) if archive_version > target_version => {
+                warn!(
+                    "{} section was taken from gravity schema version {} or newer, restoring into version {}",
+                    table, archive_version, target_version
+                )
+            }
+            (Some(archive_version), _) => debug!(
+                "{} section implies gravity schema version {} or newer",
+                table, archive_version
+            ),
+            _ => {}
+        }
+
+        let dropped: Vec<&str> = self
+            .archive_fields
+            .iter()
+            .filter(|field| !self.bound_columns.contains(*field))
+            .map(String::as_str)
+            .collect();
+        if !self.bound_columns.is_empty() && !dropped.is_empty() {
+            warn!(
+                "{}: dropped fields not restorable to the target schema: {}",
+                table,
+                dropped.join(", ")
+            );
+        }
+
+        let mut defaulted: Vec<&str> = target_columns
+            .iter()
+            .filter(|column| !self.bound_columns.contains(*column))
+            .map(String::as_str)
+            .collect();
+        defaulted.sort_unstable();
+        if !self.bound_columns.is_empty() && !defaulted.is_empty() {
+            info!(
+                "{}: columns missing in the archive set to their defaults: {}",
+                table,
+                defaulted.join(", ")
+            );
+        }
+    }
+}
+
+/// Deserializes the JSON array in the given reader element by element, handing over the
+/// records to the callback in batches of at most `batch_size` records. Returns the sum of the
+/// counts returned by the callback.
+pub fn stream_records<T, R, F>(
+    file: R,
+    batch_size: usize,
+    mut on_batch: F,
+) -> Result<i32, Box<dyn Error>>
+where
+    T: DeserializeOwned,
+    R: Read,
+    F: FnMut(Vec<T>) -> Result<i32, Box<dyn Error>>,
+{
+    // serde_json reads byte by byte, which is slow on an unbuffered tar entry
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+    let processed_count = deserializer.deserialize_seq(BatchVisitor {
+        batch_size: batch_size.max(1),
+        on_batch: &mut on_batch,
+        record_type: PhantomData,
+    })?;
+    deserializer.end()?;
+
+    Ok(processed_count)
+}
+
+struct BatchVisitor<'a, T, F> {
+    batch_size: usize,
+    on_batch: &'a mut F,
+    record_type: PhantomData<T>,
+}
+
+impl<'de, 'a, T, F> Visitor<'de> for BatchVisitor<'a, T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(Vec<T>) -> Result<i32, Box<dyn Error>>,
+{
+    type Value = i32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let capacity = self.batch_size.min(1024);
+        let mut batch: Vec<T> = Vec::with_capacity(capacity);
+        let mut processed_count = 0;
+
+        while let Some(record) = seq.next_element::<T>()? {
+            batch.push(record);
+            if batch.len() >= self.batch_size {
+                let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(capacity));
+                processed_count += (self.on_batch)(full_batch).map_err(de::Error::custom)?;
+            }
+        }
+
+        if !batch.is_empty() {
+            processed_count += (self.on_batch)(batch).map_err(de::Error::custom)?;
+        }
+
+        Ok(processed_count)
+    }
+}
+
 trait Restorable {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>>;
+    type Record: DeserializeOwned;
+
+    /// Name of the gravity table the records are restored into
+    fn table(&self) -> &'static str;
+
+    /// Column values of the given record. Fields that only exist in some Pi-hole releases are
+    /// left out when they are missing in the archive, so that the column default applies.
+    fn values<'a>(&'a self, record: &'a Self::Record) -> Vec<(&'static str, &'a dyn ToSql)>;
+
+    /// Rewrites the given record into the form Pihole expects, returning whether it changed
+    fn normalise(&self, _record: &mut Self::Record) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Checks whether the given record can be restored into a table with the given columns
+    /// without changing its meaning
+    fn check(
+        &self,
+        _record: &Self::Record,
+        _target_columns: &HashSet<String>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+    /// Checks whether the given record belongs to the groups being restored
+    fn in_scope(&self, _record: &Self::Record, _scope: &GroupScope) -> bool {
+        true
+    }
+
+    /// Group assignment table and column in which Pihole links a newly inserted record to the
+    /// Default group, along with the id of the record
+    fn default_group_link(
+        &self,
+        _record: &Self::Record,
+    ) -> Option<(&'static str, &'static str, i32)> {
+        None
+    }
+
+    /// Id of the entry the target already has for the given record, looked up by the unique key
+    /// of the table, which can differ from the id in the archive
+    fn existing_id(
+        &self,
+        _conn: &Connection,
+        _record: &Self::Record,
+    ) -> rusqlite::Result<Option<i32>> {
+        Ok(None)
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Pushes the value of an optional field only when it is present in the archive
+fn push_if_present<'a, T: ToSql>(
+    values: &mut Vec<(&'static str, &'a dyn ToSql)>,
+    column: &'static str,
+    value: &'a Option<T>,
+) {
+    if let Some(value) = value {
+        values.push((column, value));
+    }
+}
+
+#[derive(Debug)]
 struct DomainList {
     pub domain_type: i32,
-    pub list: Vec<Domain>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -169,45 +785,95 @@ struct Domain {
     pub enabled: i32,
     pub date_added: i64,
     pub comment: Option<String>, // a field that can be nullable
+    pub date_modified: Option<i64>,
 }
 
 impl Restorable for DomainList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring domainlist table");
+    type Record = Domain;
+
+    fn table(&self) -> &'static str {
+        "domainlist"
+    }
 
-        let sql = format!("INSERT OR IGNORE INTO domainlist (id,domain,enabled,date_added,comment,type) VALUES (:id,:domain,:enabled,:date_added,:comment,{});", self.domain_type);
-        let mut stmt = conn.prepare(&sql)?;
+    fn values<'a>(&'a self, record: &'a Domain) -> Vec<(&'static str, &'a dyn ToSql)> {
+        let mut values: Vec<(&'static str, &dyn ToSql)> = vec![
+            ("id", &record.id),
+            ("type", &self.domain_type),
+            ("domain", &record.domain),
+            ("enabled", &record.enabled),
+            ("date_added", &record.date_added),
+            ("comment", &record.comment),
+        ];
+        push_if_present(&mut values, "date_modified", &record.date_modified);
+        values
+    }
 
-        let record_count = self.list.len() as i32;
-        debug!("starting to load {} records to domainlist", record_count);
+    /// Exact entries are normalised, so that they can match the queries FTL sees
+    fn normalise(&self, record: &mut Domain) -> Result<bool, String> {
+        if self.domain_type != DomainType::Whitelist as i32
+            && self.domain_type != DomainType::Blacklist as i32
+        {
+            return Ok(false);
+        }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":id": &record.id,
-                ":domain": &record.domain,
-                ":enabled": &record.enabled,
-                ":date_added": &record.date_added,
-                ":comment": &record.comment,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!("error while inserting an entry to domainlist table: {}", e);
+        normalise_domain_field(&mut record.domain, record.id)
+    }
+
+    /// Regex entries are compiled before they are inserted, since FTL only logs the ones it
+    /// can't compile and otherwise ignores them
+    fn check(&self, record: &Domain, _target_columns: &HashSet<String>) -> Result<(), String> {
+        if self.domain_type != DomainType::WhitelistRegex as i32
+            && self.domain_type != DomainType::BlacklistRegex as i32
+        {
+            return Ok(());
+        }
+
+        match FtlRegex::parse(&record.domain) {
+            Ok(regex) => {
+                if !regex.unknown.is_empty() {
+                    warn!(
+                        "regex {} (id {}) has extensions FTL ignores: {}",
+                        record.domain,
+                        record.id,
+                        regex.unknown.join(", ")
+                    );
+                }
+                if let Some(reason) = regex.unsupported {
+                    warn!(
+                        "regex {} (id {}) could not be compiled the way FTL does, restoring it unchecked: {}",
+                        record.domain, record.id, reason
+                    );
                 }
+                Ok(())
             }
+            Err(e) => Err(format!(
+                "invalid regex {} (id {}): {}",
+                record.domain, record.id, e
+            )),
         }
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &Domain, scope: &GroupScope) -> bool {
+        scope.domainlist_ids.contains(&record.id)
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct AdList {
-    pub list: Vec<Ad>,
+    fn default_group_link(&self, record: &Domain) -> Option<(&'static str, &'static str, i32)> {
+        Some(("domainlist_by_group", "domainlist_id", record.id))
+    }
+
+    fn existing_id(&self, conn: &Connection, record: &Domain) -> rusqlite::Result<Option<i32>> {
+        conn.query_row(
+            "SELECT id FROM domainlist WHERE domain = ? AND type = ?",
+            params![record.domain, self.domain_type],
+            |row| row.get(0),
+        )
+        .optional()
+    }
 }
 
+#[derive(Debug)]
+struct AdList;
+
 #[derive(Debug, Deserialize)]
 struct Ad {
     pub id: i32,
@@ -215,45 +881,73 @@ struct Ad {
     pub enabled: i32,
     pub date_added: i64,
     pub comment: Option<String>,
+    pub date_modified: Option<i64>,
+    pub date_updated: Option<i64>,
+    pub number: Option<i64>,
+    pub invalid_domains: Option<i64>,
+    pub status: Option<i32>,
+    pub abp_entries: Option<i64>,
+    #[serde(rename = "type")]
+    pub list_type: Option<i32>, // 0 for block lists, 1 for allow lists
 }
 
 impl Restorable for AdList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring adlist table");
+    type Record = Ad;
 
-        let sql = "INSERT OR IGNORE INTO adlist (id,address,enabled,date_added,comment) VALUES (:id,:address,:enabled,:date_added,:comment);".to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    fn table(&self) -> &'static str {
+        "adlist"
+    }
 
-        let record_count = self.list.len() as i32;
-        debug!("starting to load {} records to adlist", record_count);
+    fn values<'a>(&'a self, record: &'a Ad) -> Vec<(&'static str, &'a dyn ToSql)> {
+        let mut values: Vec<(&'static str, &dyn ToSql)> = vec![
+            ("id", &record.id),
+            ("address", &record.address),
+            ("enabled", &record.enabled),
+            ("date_added", &record.date_added),
+            ("comment", &record.comment),
+        ];
+        push_if_present(&mut values, "date_modified", &record.date_modified);
+        push_if_present(&mut values, "date_updated", &record.date_updated);
+        push_if_present(&mut values, "number", &record.number);
+        push_if_present(&mut values, "invalid_domains", &record.invalid_domains);
+        push_if_present(&mut values, "status", &record.status);
+        push_if_present(&mut values, "abp_entries", &record.abp_entries);
+        push_if_present(&mut values, "type", &record.list_type);
+        values
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":id": &record.id,
-                ":address": &record.address,
-                ":enabled": &record.enabled,
-                ":date_added": &record.date_added,
-                ":comment": &record.comment,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!("error while inserting an entry to adlist table: {}", e);
-                }
-            }
+    fn check(&self, record: &Ad, target_columns: &HashSet<String>) -> Result<(), String> {
+        // without the type column, an allow list would be restored as a block list
+        match record.list_type {
+            Some(list_type) if list_type != 0 && !target_columns.contains("type") => Err(format!(
+                "{} is an allow list, which the target schema doesn't support",
+                record.address
+            )),
+            _ => Ok(()),
         }
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &Ad, scope: &GroupScope) -> bool {
+        scope.adlist_ids.contains(&record.id)
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct DomainAuditList {
-    pub list: Vec<DomainAuditEntry>,
+    fn default_group_link(&self, record: &Ad) -> Option<(&'static str, &'static str, i32)> {
+        Some(("adlist_by_group", "adlist_id", record.id))
+    }
+
+    fn existing_id(&self, conn: &Connection, record: &Ad) -> rusqlite::Result<Option<i32>> {
+        conn.query_row(
+            "SELECT id FROM adlist WHERE address = ?",
+            params![record.address],
+            |row| row.get(0),
+        )
+        .optional()
+    }
 }
 
+#[derive(Debug)]
+struct DomainAuditList;
+
 #[derive(Debug, Deserialize)]
 struct DomainAuditEntry {
     pub id: i32,
@@ -262,88 +956,84 @@ struct DomainAuditEntry {
 }
 
 impl Restorable for DomainAuditList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring domain_audit table");
-
-        let sql = "INSERT OR IGNORE INTO domain_audit (id,domain,date_added) VALUES (:id,:domain,:date_added);".to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    type Record = DomainAuditEntry;
 
-        let record_count = self.list.len() as i32;
-        debug!("starting to load {} records to domain_audit", record_count);
+    fn table(&self) -> &'static str {
+        "domain_audit"
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":id": &record.id,
-                ":domain": &record.domain,
-                ":date_added": &record.date_added,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!(
-                        "error while inserting an entry to domain_audit table: {}",
-                        e
-                    );
-                }
-            }
-        }
+    fn values<'a>(&'a self, record: &'a DomainAuditEntry) -> Vec<(&'static str, &'a dyn ToSql)> {
+        vec![
+            ("id", &record.id),
+            ("domain", &record.domain),
+            ("date_added", &record.date_added),
+        ]
+    }
 
-        Ok(processed_count)
+    fn normalise(&self, record: &mut DomainAuditEntry) -> Result<bool, String> {
+        normalise_domain_field(&mut record.domain, record.id)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct GroupList {
-    pub list: Vec<Group>,
+/// Replaces the given domain of the record with the given archive id with its normalised
+/// form, see `domain::normalise_domain`
+fn normalise_domain_field(field: &mut String, id: i32) -> Result<bool, String> {
+    let normalised = domain::normalise_domain(field)
+        .map_err(|e| format!("invalid domain {:?} (id {}): {}", field, id, e))?;
+    if let Some(reason) = domain::questionable_domain(&normalised) {
+        warn!("domain {} (id {}) is {}", normalised, id, reason);
+    }
+    if normalised == *field {
+        return Ok(false);
+    }
+
+    info!(
+        "normalised domain {:?} to {} (id {})",
+        field, normalised, id
+    );
+    *field = normalised;
+    Ok(true)
 }
 
+#[derive(Debug)]
+struct GroupList;
+
 #[derive(Debug, Deserialize)]
 struct Group {
     pub id: i32,
     pub name: String,
     pub date_added: i64,
     pub description: Option<String>, // this field can be null
+    pub enabled: Option<i32>,
+    pub date_modified: Option<i64>,
 }
 
 impl Restorable for GroupList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring group table");
-
-        let sql =
-            "INSERT OR IGNORE INTO \"group\" (id,name,date_added,description) VALUES (:id,:name,:date_added,:description);"
-                .to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    type Record = Group;
 
-        let record_count = self.list.len() as i32;
-        debug!("starting to load {} records to group", record_count);
+    fn table(&self) -> &'static str {
+        "group"
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":id": &record.id,
-                ":name": &record.name,
-                ":date_added": &record.date_added,
-                ":description": &record.description,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!("error while inserting an entry to group table: {}", e);
-                }
-            }
-        }
+    fn values<'a>(&'a self, record: &'a Group) -> Vec<(&'static str, &'a dyn ToSql)> {
+        let mut values: Vec<(&'static str, &dyn ToSql)> = vec![
+            ("id", &record.id),
+            ("name", &record.name),
+            ("date_added", &record.date_added),
+            ("description", &record.description),
+        ];
+        push_if_present(&mut values, "enabled", &record.enabled);
+        push_if_present(&mut values, "date_modified", &record.date_modified);
+        values
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &Group, scope: &GroupScope) -> bool {
+        scope.group_ids.contains(&record.id)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct ClientList {
-    pub list: Vec<Client>,
-}
+#[derive(Debug)]
+struct ClientList;
 
 #[derive(Debug, Deserialize)]
 struct Client {
@@ -351,46 +1041,48 @@ struct Client {
     pub ip: String,
     pub date_added: i64,
     pub comment: Option<String>,
+    pub date_modified: Option<i64>,
 }
 
 impl Restorable for ClientList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring client table");
+    type Record = Client;
 
-        let sql =
-            "INSERT OR IGNORE INTO client (id,ip,date_added,comment) VALUES (:id,:ip,:date_added,:comment);"
-                .to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    fn table(&self) -> &'static str {
+        "client"
+    }
 
-        let record_count = self.list.len() as i32;
-        debug!("starting to load {} records to client", record_count);
+    fn values<'a>(&'a self, record: &'a Client) -> Vec<(&'static str, &'a dyn ToSql)> {
+        let mut values: Vec<(&'static str, &dyn ToSql)> = vec![
+            ("id", &record.id),
+            ("ip", &record.ip),
+            ("date_added", &record.date_added),
+            ("comment", &record.comment),
+        ];
+        push_if_present(&mut values, "date_modified", &record.date_modified);
+        values
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":id": &record.id,
-                ":ip": &record.ip,
-                ":date_added": &record.date_added,
-                ":comment": &record.comment,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!("error while inserting an entry to group table: {}", e);
-                }
-            }
-        }
+    fn in_scope(&self, record: &Client, scope: &GroupScope) -> bool {
+        scope.client_ids.contains(&record.id)
+    }
 
-        Ok(processed_count)
+    fn default_group_link(&self, record: &Client) -> Option<(&'static str, &'static str, i32)> {
+        Some(("client_by_group", "client_id", record.id))
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct ClientGroupAssignmentList {
-    pub list: Vec<ClientGroupAssignment>,
+    fn existing_id(&self, conn: &Connection, record: &Client) -> rusqlite::Result<Option<i32>> {
+        conn.query_row(
+            "SELECT id FROM client WHERE ip = ?",
+            params![record.ip],
+            |row| row.get(0),
+        )
+        .optional()
+    }
 }
 
+#[derive(Debug)]
+struct ClientGroupAssignmentList;
+
 #[derive(Debug, Deserialize)]
 struct ClientGroupAssignment {
     pub client_id: i32,
@@ -398,46 +1090,29 @@ struct ClientGroupAssignment {
 }
 
 impl Restorable for ClientGroupAssignmentList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring client_by_group table");
-
-        let sql =
-            "INSERT OR IGNORE INTO client_by_group (client_id,group_id) VALUES (:client_id,:group_id);"
-                .to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    type Record = ClientGroupAssignment;
 
-        let record_count = self.list.len() as i32;
-        debug!(
-            "starting to load {} records to client_by_group",
-            record_count
-        );
+    fn table(&self) -> &'static str {
+        "client_by_group"
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":client_id": &record.client_id,
-                ":group_id": &record.group_id,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!(
-                        "error while inserting an entry to client_by_group table: {}",
-                        e
-                    );
-                }
-            }
-        }
+    fn values<'a>(
+        &'a self,
+        record: &'a ClientGroupAssignment,
+    ) -> Vec<(&'static str, &'a dyn ToSql)> {
+        vec![
+            ("client_id", &record.client_id),
+            ("group_id", &record.group_id),
+        ]
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &ClientGroupAssignment, scope: &GroupScope) -> bool {
+        scope.group_ids.contains(&record.group_id)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct DomainListGroupAssignmentList {
-    pub list: Vec<DomainListGroupAssignment>,
-}
+#[derive(Debug)]
+struct DomainListGroupAssignmentList;
 
 #[derive(Debug, Deserialize)]
 struct DomainListGroupAssignment {
@@ -446,46 +1121,29 @@ struct DomainListGroupAssignment {
 }
 
 impl Restorable for DomainListGroupAssignmentList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring domainlist_by_group table");
-
-        let sql =
-            "INSERT OR IGNORE INTO domainlist_by_group (domainlist_id,group_id) VALUES (:domainlist_id,:group_id);"
-                .to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    type Record = DomainListGroupAssignment;
 
-        let record_count = self.list.len() as i32;
-        debug!(
-            "starting to load {} records to domainlist_by_group",
-            record_count
-        );
+    fn table(&self) -> &'static str {
+        "domainlist_by_group"
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":domainlist_id": &record.domainlist_id,
-                ":group_id": &record.group_id,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!(
-                        "error while inserting an entry to domainlist_by_group table: {}",
-                        e
-                    );
-                }
-            }
-        }
+    fn values<'a>(
+        &'a self,
+        record: &'a DomainListGroupAssignment,
+    ) -> Vec<(&'static str, &'a dyn ToSql)> {
+        vec![
+            ("domainlist_id", &record.domainlist_id),
+            ("group_id", &record.group_id),
+        ]
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &DomainListGroupAssignment, scope: &GroupScope) -> bool {
+        scope.group_ids.contains(&record.group_id)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct AdListGroupAssignmentList {
-    pub list: Vec<AdListGroupAssignment>,
-}
+#[derive(Debug)]
+struct AdListGroupAssignmentList;
 
 #[derive(Debug, Deserialize)]
 struct AdListGroupAssignment {
@@ -494,38 +1152,23 @@ struct AdListGroupAssignment {
 }
 
 impl Restorable for AdListGroupAssignmentList {
-    fn restore_table(&self, conn: Connection) -> Result<i32, Box<dyn Error>> {
-        debug!("restoring adlist_by_group table");
-
-        let sql =
-            "INSERT OR IGNORE INTO adlist_by_group (adlist_id,group_id) VALUES (:adlist_id,:group_id);"
-                .to_string();
-        let mut stmt = conn.prepare(&sql)?;
+    type Record = AdListGroupAssignment;
 
-        let record_count = self.list.len() as i32;
-        debug!(
-            "starting to load {} records to adlist_by_group",
-            record_count
-        );
+    fn table(&self) -> &'static str {
+        "adlist_by_group"
+    }
 
-        let mut processed_count = 0;
-        for record in &self.list {
-            let result = stmt.execute(named_params! {
-                ":adlist_id": &record.adlist_id,
-                ":group_id": &record.group_id,
-            });
-
-            match result {
-                Ok(_) => processed_count += 1,
-                Err(e) => {
-                    warn!(
-                        "error while inserting an entry to adlist_by_group table: {}",
-                        e
-                    );
-                }
-            }
-        }
+    fn values<'a>(
+        &'a self,
+        record: &'a AdListGroupAssignment,
+    ) -> Vec<(&'static str, &'a dyn ToSql)> {
+        vec![
+            ("adlist_id", &record.adlist_id),
+            ("group_id", &record.group_id),
+        ]
+    }
 
-        Ok(processed_count)
+    fn in_scope(&self, record: &AdListGroupAssignment, scope: &GroupScope) -> bool {
+        scope.group_ids.contains(&record.group_id)
     }
 }
@@ -0,0 +1,421 @@
+use crate::pihole::dns;
+use crate::pihole::domain;
+use crate::pihole::files;
+use crate::pihole::wildcard;
+use flate2::read::GzDecoder;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+
+pub const HOSTS_FILE: &str = "/etc/hosts";
+
+/// names the system maps to loopback and multicast addresses, which are never restored
+const SYSTEM_NAMES: [&str; 4] = [
+    "localhost",
+    "localhost.localdomain",
+    "broadcasthost",
+    "ip6-*",
+];
+
+/// An `<IP> <NAME> [<NAME>...]` line in a hosts file
+#[derive(Debug, Clone)]
+pub struct HostsEntry {
+    pub ip: IpAddr,
+    pub names: Vec<String>,
+}
+
+/// A line of a hosts file, as used by /etc/hosts and Pihole's custom.list
+#[derive(Debug, Clone)]
+pub enum HostsLine {
+    /// an entry with the comment following it on the same line, if any. Entries read from a
+    /// file are rendered with their original text.
+    Entry {
+        entry: HostsEntry,
+        comment: Option<String>,
+        text: String,
+    },
+    /// comments and blank lines
+    Other(String),
+    /// lines that could not be parsed, which are kept as they are when the file is rewritten
+    Invalid(String),
+}
+
+/// A line or part of a line that could not be parsed
+#[derive(Debug)]
+pub struct RejectedLine {
+    pub line_number: usize,
+    pub line: String,
+    pub reason: String,
+}
+
+/// The lines of a hosts file, in order
+#[derive(Debug, Default)]
+pub struct HostsFile {
+    pub lines: Vec<HostsLine>,
+}
+
+impl HostsFile {
+    /// Parses the given hosts file contents, returning the lines that were rejected along with
+    /// the file. Addresses are IPv4 or IPv6, names have to be valid as per RFC 1123, and a line
+    /// can map any number of names to the same address.
+    pub fn parse(contents: &str) -> (HostsFile, Vec<RejectedLine>) {
+        let mut hosts_file = HostsFile::default();
+        let mut rejected: Vec<RejectedLine> = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let reject = |reason: String| RejectedLine {
+                line_number: index + 1,
+                line: line.to_string(),
+                reason,
+            };
+
+            let (content, comment) = match line.split_once('#') {
+                Some((content, comment)) => (content, Some(comment.trim().to_string())),
+                None => (line, None),
+            };
+
+            let mut fields = content.split_whitespace();
+            let ip = match fields.next() {
+                Some(ip) => ip,
+                None => {
+                    hosts_file.lines.push(HostsLine::Other(line.to_string()));
+                    continue;
+                }
+            };
+
+            let ip = match ip.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    rejected.push(reject(format!("invalid IP address {}", ip)));
+                    hosts_file.lines.push(HostsLine::Invalid(line.to_string()));
+                    continue;
+                }
+            };
+
+            let mut names: Vec<String> = Vec::new();
+            for name in fields {
+                if domain::is_valid_hostname(name) {
+                    names.push(name.to_string());
+                } else {
+                    rejected.push(reject(format!("invalid host name {}", name)));
+                }
+            }
+
+            if names.is_empty() {
+                if content.split_whitespace().count() == 1 {
+                    rejected.push(reject(String::from("no host names")));
+                }
+                hosts_file.lines.push(HostsLine::Invalid(line.to_string()));
+                continue;
+            }
+
+            hosts_file.lines.push(HostsLine::Entry {
+                entry: HostsEntry { ip, names },
+                comment: comment.filter(|c| !c.is_empty()),
+                text: line.to_string(),
+            });
+        }
+        (hosts_file, rejected)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HostsEntry> {
+        self.lines.iter().filter_map(|line| match line {
+            HostsLine::Entry { entry, .. } => Some(entry),
+            _ => None,
+        })
+    }
+
+    /// Returns the address the given name is mapped to, out of the ones of the same address
+    /// family as the given address
+    pub fn ip_of(&self, name: &str, ip: &IpAddr) -> Option<IpAddr> {
+        current_ip_of(self.entries(), name, ip)
+    }
+
+    /// Appends the given entry as a new `<IP> <NAME>...` line
+    pub fn push(&mut self, entry: HostsEntry, comment: Option<String>) {
+        let text = format_entry(&entry, &comment);
+        self.lines.push(HostsLine::Entry {
+            entry,
+            comment,
+            text,
+        });
+    }
+
+    /// Removes the given name from the entries of the same address family as the given address,
+    /// dropping the entries left without names
+    pub fn remove_name(&mut self, name: &str, ip: &IpAddr) {
+        for line in self.lines.iter_mut() {
+            if let HostsLine::Entry {
+                entry,
+                comment,
+                text,
+            } = line
+            {
+                let before = entry.names.len();
+                if entry.ip.is_ipv4() == ip.is_ipv4() {
+                    entry.names.retain(|n| !n.eq_ignore_ascii_case(name));
+                }
+                if entry.names.len() != before {
+                    *text = format_entry(entry, comment);
+                }
+            }
+        }
+        self.lines.retain(|line| match line {
+            HostsLine::Entry { entry, .. } => !entry.names.is_empty(),
+            _ => true,
+        });
+    }
+
+    pub fn render(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                HostsLine::Entry { text, .. } => contents.push_str(text),
+                HostsLine::Other(text) | HostsLine::Invalid(text) => contents.push_str(text),
+            }
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+fn format_entry(entry: &HostsEntry, comment: &Option<String>) -> String {
+    let mut text = format!("{} {}", entry.ip, entry.names.join(" "));
+    if let Some(comment) = comment {
+        text.push_str(" # ");
+        text.push_str(comment);
+    }
+    text
+}
+
+impl HostsEntry {
+    /// Whether this is a line the system manages, ex: `127.0.0.1 localhost`
+    fn is_system_entry(&self) -> bool {
+        let system_ip = match self.ip {
+            IpAddr::V4(ip) => ip.is_loopback() || ip.is_unspecified(),
+            IpAddr::V6(ip) => {
+                ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xff00) == 0xff00
+            }
+        };
+        let system_name = self.names.iter().any(|name| {
+            SYSTEM_NAMES
+                .iter()
+                .any(|pattern| wildcard::matches(pattern, name))
+        });
+        system_ip || system_name
+    }
+}
+
+/// Merges the entries of the archived hosts file into the hosts file of this system. Entries
+/// for names that are already mapped are left untouched, as are the loopback and other system
+/// entries. Returns the number of names added.
+pub fn merge_hosts(file: &mut tar::Entry<'_, GzDecoder<File>>) -> Result<i32, Box<dyn Error>> {
+    let incoming_entries = read_archived_entries(file)?;
+
+    let current = files::read_or_empty(HOSTS_FILE)?;
+    let mut current_entries = parse_hosts(&current);
+
+    let mut new_lines: Vec<String> = Vec::new();
+    let mut added_count = 0;
+    for entry in incoming_entries {
+        let mut new_names: Vec<&str> = Vec::new();
+        for name in &entry.names {
+            match current_ip_of(&current_entries, name, &entry.ip) {
+                Some(ip) if ip == entry.ip => {
+                    debug!("hosts entry already exists: {} {}", entry.ip, name)
+                }
+                Some(ip) => warn!(
+                    "not restoring hosts entry {} {} since {} is mapped to {} on this system",
+                    entry.ip, name, name, ip
+                ),
+                None => new_names.push(name),
+            }
+        }
+
+        if !new_names.is_empty() {
+            info!("hosts: + {} {}", entry.ip, new_names.join(" "));
+            added_count += new_names.len() as i32;
+            new_lines.push(format!("{} {}", entry.ip, new_names.join(" ")));
+
+            // names repeated further down in the archived file are then skipped
+            current_entries.push(HostsEntry {
+                ip: entry.ip,
+                names: new_names.iter().map(|name| name.to_string()).collect(),
+            });
+        }
+    }
+
+    if new_lines.is_empty() {
+        return Ok(0);
+    }
+
+    let mut contents = current;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for line in new_lines {
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    files::write_atomically(HOSTS_FILE, &contents)?;
+
+    Ok(added_count)
+}
+
+/// Adds the entries of the archived hosts file as Local DNS records instead of merging them
+/// into the hosts file of this system. Returns the number of records added.
+pub fn convert_to_local_dns(
+    file: &mut tar::Entry<'_, GzDecoder<File>>,
+) -> Result<i32, Box<dyn Error>> {
+    let incoming_entries = read_archived_entries(file)?;
+    dns::add_hosts_entries(&incoming_entries)
+}
+
+/// Reads the entries of the archived hosts file, leaving out the system entries
+fn read_archived_entries(
+    file: &mut tar::Entry<'_, GzDecoder<File>>,
+) -> Result<Vec<HostsEntry>, Box<dyn Error>> {
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+
+    Ok(parse_hosts(&s)
+        .into_iter()
+        .filter(|entry| {
+            if entry.is_system_entry() {
+                debug!(
+                    "skipping system hosts entry: {} {:?}",
+                    entry.ip, entry.names
+                );
+                false
+            } else {
+                true
+            }
+        })
+        .collect())
+}
+
+/// Returns the address the given name is mapped to in the given entries, out of the ones of
+/// the same address family as the given address, since a name can have one of each
+fn current_ip_of<'a>(
+    entries: impl IntoIterator<Item = &'a HostsEntry>,
+    name: &str,
+    ip: &IpAddr,
+) -> Option<IpAddr> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.ip.is_ipv4() == ip.is_ipv4())
+        .find(|entry| entry.names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+        .map(|entry| entry.ip)
+}
+
+/// Parses the entries in the given hosts file contents. Comments and blank lines are skipped,
+/// and lines without a valid IP address or name are logged with their line number and skipped.
+pub fn parse_hosts(contents: &str) -> Vec<HostsEntry> {
+    let (hosts_file, rejected) = HostsFile::parse(contents);
+    for r in rejected {
+        warn!(
+            "invalid entry found while reading hosts file at line {}: {} ({})",
+            r.line_number, r.line, r.reason
+        );
+    }
+    hosts_file.entries().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(hosts_file: &HostsFile) -> Vec<Vec<String>> {
+        hosts_file
+            .entries()
+            .map(|entry| entry.names.clone())
+            .collect()
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_kept() {
+        let contents = "# local hosts\n\n10.0.0.1 nas # storage\n   \n";
+        let (hosts_file, rejected) = HostsFile::parse(contents);
+        assert!(rejected.is_empty());
+        assert_eq!(hosts_file.lines.len(), 4);
+        assert!(matches!(hosts_file.lines[0], HostsLine::Other(_)));
+        match &hosts_file.lines[2] {
+            HostsLine::Entry { entry, comment, .. } => {
+                assert_eq!(entry.names, vec!["nas"]);
+                assert_eq!(comment.as_deref(), Some("storage"));
+            }
+            line => panic!("expected an entry, got {:?}", line),
+        }
+        assert_eq!(
+            hosts_file.render(),
+            "# local hosts\n\n10.0.0.1 nas # storage\n   \n"
+        );
+    }
+
+    #[test]
+    fn fields_can_be_separated_by_tabs() {
+        let (hosts_file, rejected) = HostsFile::parse("10.0.0.1\tnas\t nas.lan\n");
+        assert!(rejected.is_empty());
+        assert_eq!(names(&hosts_file), vec![vec!["nas", "nas.lan"]]);
+        assert_eq!(hosts_file.render(), "10.0.0.1\tnas\t nas.lan\n");
+    }
+
+    #[test]
+    fn ipv6_addresses_are_parsed() {
+        let (hosts_file, rejected) = HostsFile::parse("fd00::1 nas\n::1 ip6-localhost\n");
+        assert!(rejected.is_empty());
+        let entries: Vec<&HostsEntry> = hosts_file.entries().collect();
+        assert_eq!(entries[0].ip, "fd00::1".parse::<IpAddr>().unwrap());
+        assert!(entries[1].is_system_entry());
+        assert!(!entries[0].is_system_entry());
+    }
+
+    #[test]
+    fn invalid_lines_are_kept_as_they_are() {
+        let contents = "10.0.0.300 nas\n10.0.0.1\n10.0.0.2 -bad\n10.0.0.3 ok -bad\n";
+        let (hosts_file, rejected) = HostsFile::parse(contents);
+        let reasons: Vec<(usize, &str)> = rejected
+            .iter()
+            .map(|r| (r.line_number, r.reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, "invalid IP address 10.0.0.300"),
+                (2, "no host names"),
+                (3, "invalid host name -bad"),
+                (4, "invalid host name -bad"),
+            ]
+        );
+        assert!(matches!(hosts_file.lines[0], HostsLine::Invalid(_)));
+        assert!(matches!(hosts_file.lines[1], HostsLine::Invalid(_)));
+        assert!(matches!(hosts_file.lines[2], HostsLine::Invalid(_)));
+        assert_eq!(names(&hosts_file), vec![vec!["ok"]]);
+        assert_eq!(hosts_file.render(), contents);
+    }
+
+    #[test]
+    fn names_are_looked_up_per_address_family() {
+        let (hosts_file, _) = HostsFile::parse("10.0.0.1 nas\nfd00::1 NAS\n");
+        let v4: IpAddr = "10.0.0.9".parse().unwrap();
+        let v6: IpAddr = "fd00::9".parse().unwrap();
+        assert_eq!(
+            hosts_file.ip_of("NAS", &v4),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            hosts_file.ip_of("nas", &v6),
+            Some("fd00::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn removing_the_last_name_drops_the_entry() {
+        let (mut hosts_file, _) = HostsFile::parse("10.0.0.1 nas # storage\n10.0.0.2 a b\n");
+        let v4: IpAddr = "10.0.0.9".parse().unwrap();
+        hosts_file.remove_name("nas", &v4);
+        hosts_file.remove_name("a", &v4);
+        assert_eq!(hosts_file.render(), "10.0.0.2 b\n");
+    }
+}
@@ -0,0 +1,183 @@
+use crate::pihole::archive;
+use crate::pihole::gravity;
+use crate::pihole::inspect;
+use crate::pihole::schema;
+use log::warn;
+use regex::Regex;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use serde_json::Map;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// What an archive entry holds, as far as it can be told without restoring it
+struct EntryInfo {
+    name: String,
+    size: u64,
+    /// JSON records, or option lines of config files
+    records: Option<i64>,
+    newest_date_added: Option<i64>,
+    supported: bool,
+}
+
+/// Prints the entries of the given archive with the number of records they hold, along with
+/// what can be told about where the archive was taken from, without writing anything
+pub fn print_info(archive_file: &str) -> Result<(), Box<dyn Error>> {
+    println!("archive: {}", archive_file);
+    let mut magic = [0u8; 4];
+    let read = File::open(archive_file)?.read(&mut magic)?;
+    if read == 4 && magic == *b"PK\x03\x04" {
+        println!("format: zip, as taken by the Pi-hole v6 Teleporter, which can't be restored");
+        return Ok(());
+    }
+
+    let mut entries: Vec<EntryInfo> = Vec::new();
+    let mut adlist_fields: BTreeSet<String> = BTreeSet::new();
+    let mut archive = archive::open(archive_file)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut info = EntryInfo {
+            supported: archive::is_supported(&name),
+            size: entry.header().size()?,
+            records: None,
+            newest_date_added: None,
+            name,
+        };
+
+        if info.name.ends_with(".json") {
+            let mut fields: BTreeSet<String> = BTreeSet::new();
+            let mut newest: Option<i64> = None;
+            let result = gravity::stream_records(
+                &mut entry,
+                gravity::DEFAULT_BATCH_SIZE,
+                |batch: Vec<Map<String, serde_json::Value>>| {
+                    for record in &batch {
+                        fields.extend(record.keys().cloned());
+                        if let Some(date_added) = record.get("date_added").and_then(|d| d.as_i64())
+                        {
+                            newest = newest.max(Some(date_added));
+                        }
+                    }
+                    Ok(batch.len() as i32)
+                },
+            );
+            match result {
+                Ok(count) => info.records = Some(count as i64),
+                Err(e) => warn!("{} is not a list of records: {}", info.name, e),
+            }
+            info.newest_date_added = newest;
+            if info.name == "adlist.json" {
+                adlist_fields = fields;
+            }
+        } else {
+            let mut contents = String::new();
+            if entry.read_to_string(&mut contents).is_ok() {
+                let options = contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .count();
+                info.records = Some(options as i64);
+            }
+        }
+        entries.push(info);
+    }
+
+    match schema::implied_version("adlist", &adlist_fields) {
+        Some(version) if version > schema::GRAVITY_SCHEMA_VERSION => println!(
+            "format: Teleporter tar.gz, from gravity schema version {} or newer (Pi-hole v6)",
+            version
+        ),
+        Some(version) => println!(
+            "format: Teleporter tar.gz, from gravity schema version {} or newer (Pi-hole v5)",
+            version
+        ),
+        None => println!("format: Teleporter tar.gz (Pi-hole v5)"),
+    }
+    let file_name = Path::new(archive_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match parse_file_name(&file_name) {
+        Some((host, taken_at)) => {
+            match host {
+                // the Teleporter replaces the dots of the host name with underscores
+                Some(host) if host.contains('_') => println!(
+                    "source host: {} (approximate, dots in the name are shown as _)",
+                    host
+                ),
+                Some(host) => println!("source host: {}", host),
+                None => println!("source host: unknown, the Teleporter couldn't read it"),
+            }
+            println!("taken at: {} (local time of the source)", taken_at);
+        }
+        None => println!("source: unknown, the file name is not the one the Teleporter gives"),
+    }
+    println!();
+
+    // timestamps are shown in UTC, formatted by SQLite
+    let conn = Connection::open_in_memory()?;
+    let columns: Vec<String> = ["entry", "bytes", "records", "newest date_added", "restored"]
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    let mut rows: Vec<Vec<Value>> = Vec::new();
+    for info in &entries {
+        let newest: Option<String> = match info.newest_date_added {
+            Some(timestamp) => Some(conn.query_row(
+                "SELECT datetime(?, 'unixepoch')",
+                [timestamp],
+                |row| row.get(0),
+            )?),
+            None => None,
+        };
+        rows.push(vec![
+            Value::Text(info.name.clone()),
+            Value::Integer(info.size as i64),
+            info.records.map_or(Value::Null, Value::Integer),
+            newest.map_or(Value::Text(String::from("-")), Value::Text),
+            Value::Text(String::from(if info.supported {
+                "yes"
+            } else {
+                "unsupported"
+            })),
+        ]);
+    }
+    inspect::print_table(&columns, &rows);
+
+    let unsupported: Vec<&str> = entries
+        .iter()
+        .filter(|info| !info.supported)
+        .map(|info| info.name.as_str())
+        .collect();
+    if !unsupported.is_empty() {
+        println!();
+        println!("not restored: {}", unsupported.join(", "));
+    }
+    Ok(())
+}
+
+/// Extracts the host name and the time the archive was taken at from the name the Teleporter
+/// gives it, ex: `pi-hole-master-teleporter_2023-05-28_14-03-11.tar.gz`. The host name is
+/// missing when the Teleporter couldn't read it, ex: `pi-hole-teleporter_2023-05-28_14-03-11.tar.gz`
+fn parse_file_name(file_name: &str) -> Option<(Option<String>, String)> {
+    let name_regex = Regex::new(
+        r"^pi-hole-(?:(.+)-)?teleporter_(\d{4}-\d{2}-\d{2})_(\d{2})-(\d{2})-(\d{2})\.tar\.gz$",
+    )
+    .unwrap();
+    let captures = name_regex.captures(file_name)?;
+    Some((
+        captures.get(1).map(|host| host.as_str().to_string()),
+        format!(
+            "{} {}:{}:{}",
+            &captures[2], &captures[3], &captures[4], &captures[5]
+        ),
+    ))
+}
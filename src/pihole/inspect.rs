@@ -0,0 +1,151 @@
+use clap::ValueEnum;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::error::Error;
+
+/// How the results of a query are printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// columns aligned under a header
+    Table,
+    /// comma separated values with a header line
+    Csv,
+    /// an array with an object per row
+    Json,
+}
+
+/// Runs the given SQL query and prints its results in the given format. Returns the number
+/// of rows.
+pub fn run_query(
+    conn: &Connection,
+    sql: &str,
+    format: OutputFormat,
+) -> Result<i32, Box<dyn Error>> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+
+    let mut rows: Vec<Vec<Value>> = Vec::new();
+    let mut result = stmt.query([])?;
+    while let Some(row) = result.next()? {
+        let mut values: Vec<Value> = Vec::with_capacity(columns.len());
+        for index in 0..columns.len() {
+            values.push(row.get(index)?);
+        }
+        rows.push(values);
+    }
+
+    match format {
+        OutputFormat::Table => print_table(&columns, &rows),
+        OutputFormat::Csv => print_csv(&columns, &rows),
+        OutputFormat::Json => print_json(&columns, &rows),
+    }
+    Ok(rows.len() as i32)
+}
+
+pub fn print_table(columns: &[String], rows: &[Vec<Value>]) {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| to_text(value).unwrap_or_else(|| String::from("NULL")))
+                .collect()
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.chars().count()).collect();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |values: &[String]| {
+        values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:<width$}", value, width = width))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(columns));
+    println!(
+        "{}",
+        widths
+            .iter()
+            .map(|width| "-".repeat(*width))
+            .collect::<Vec<String>>()
+            .join("  ")
+    );
+    for row in &cells {
+        println!("{}", line(row));
+    }
+}
+
+fn print_csv(columns: &[String], rows: &[Vec<Value>]) {
+    let quote = |field: &str| {
+        if field.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    };
+
+    println!(
+        "{}",
+        columns
+            .iter()
+            .map(|c| quote(c))
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+    for row in rows {
+        println!(
+            "{}",
+            row.iter()
+                .map(|value| quote(&to_text(value).unwrap_or_default()))
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+    }
+}
+
+/// Prints the rows as JSON objects with the keys in the order of the columns
+fn print_json(columns: &[String], rows: &[Vec<Value>]) {
+    let keys: Vec<String> = columns
+        .iter()
+        .map(|c| serde_json::Value::from(c.as_str()).to_string())
+        .collect();
+
+    println!("[");
+    for (index, row) in rows.iter().enumerate() {
+        let fields: Vec<String> = keys
+            .iter()
+            .zip(row)
+            .map(|(key, value)| format!("{}: {}", key, to_json(value)))
+            .collect();
+        let separator = if index + 1 < rows.len() { "," } else { "" };
+        println!("  {{{}}}{}", fields.join(", "), separator);
+    }
+    println!("]");
+}
+
+fn to_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Real(r) => Some(r.to_string()),
+        Value::Text(t) => Some(t.clone()),
+        Value::Blob(b) => Some(String::from_utf8_lossy(b).to_string()),
+    }
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::Value::from(*i),
+        Value::Real(r) => serde_json::Value::from(*r),
+        _ => serde_json::Value::from(to_text(value)),
+    }
+}
@@ -0,0 +1,394 @@
+use crate::pihole::archive;
+use crate::pihole::gravity::DomainType;
+use crate::pihole::regexp::FtlRegex;
+use log::{debug, info};
+use rusqlite::Connection;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+/// An issue found in an archive, printed as `check: message`
+struct Finding {
+    check: &'static str,
+    message: String,
+}
+
+/// Loads the lists of the given archive and reports contradictions and redundancies among
+/// them, so that they can be cleaned up before being restored elsewhere. Returns the number
+/// of issues found.
+pub fn lint_archive(archive_file: &str) -> Result<i32, Box<dyn Error>> {
+    let conn = archive::load_into_memory(archive_file)?;
+
+    let mut findings: Vec<Finding> = Vec::new();
+    findings.extend(contradictions(&conn)?);
+    findings.extend(covered_by_regex(&conn)?);
+    findings.extend(duplicate_regexes(&conn)?);
+    findings.extend(unassigned_disabled_entries(&conn)?);
+    findings.extend(groups_without_clients(&conn)?);
+    findings.extend(missing_groups(&conn)?);
+    findings.extend(duplicate_adlists(&conn)?);
+
+    for finding in &findings {
+        println!("{}: {}", finding.check, finding.message);
+    }
+    Ok(findings.len() as i32)
+}
+
+/// Domains on both the whitelist and the blacklist of the same kind, where the whitelist wins
+fn contradictions(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT w.domain, w.id, w.type, b.id, b.type FROM domainlist w
+         JOIN domainlist b ON lower(w.domain) = lower(b.domain)
+         WHERE (w.type = 0 AND b.type = 1) OR (w.type = 2 AND b.type = 3)
+         ORDER BY w.id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, i32>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, i32>(4)?,
+        ))
+    })?;
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for row in rows {
+        let (domain, white_id, white_type, black_id, black_type) = row?;
+        findings.push(Finding {
+            check: "contradiction",
+            message: format!(
+                "{} is on the {} (id {}) and the {} (id {})",
+                domain,
+                list_name(white_type),
+                white_id,
+                list_name(black_type),
+                black_id
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Exact entries matched by an enabled regex of the same kind which applies to at least the
+/// same groups, making the exact entry redundant
+fn covered_by_regex(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let groups = domain_groups(conn)?;
+    let no_groups = BTreeSet::new();
+
+    let mut regexes: Vec<(i64, i32, String, FtlRegex)> = Vec::new();
+    let mut stmt = conn
+        .prepare("SELECT id, type, domain FROM domainlist WHERE type IN (2, 3) AND enabled = 1")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, regex_type, pattern) = row?;
+        match FtlRegex::parse(&pattern) {
+            Ok(FtlRegex {
+                unsupported: Some(reason),
+                ..
+            }) => info!(
+                "not linting against regex {} (id {}): {}",
+                pattern, id, reason
+            ),
+            // regexes limited to some query types don't cover every query of a domain
+            Ok(regex) if regex.applies_to_all_query_types() => {
+                regexes.push((id, regex_type, pattern, regex))
+            }
+            Ok(_) => {}
+            Err(e) => debug!("not linting against regex {} (id {}): {}", pattern, id, e),
+        }
+    }
+
+    let mut findings: Vec<Finding> = Vec::new();
+    let mut stmt =
+        conn.prepare("SELECT id, type, domain FROM domainlist WHERE type IN (0, 1) ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, exact_type, domain) = row?;
+        let exact_groups = groups.get(&id).unwrap_or(&no_groups);
+        let covering = regexes.iter().find(|(regex_id, regex_type, _, regex)| {
+            // exact types are 0 and 1, their regex counterparts 2 and 3
+            *regex_type == exact_type + 2
+                && exact_groups.is_subset(groups.get(regex_id).unwrap_or(&no_groups))
+                && regex.matches(&domain, "A")
+        });
+        if let Some((regex_id, regex_type, pattern, _)) = covering {
+            findings.push(Finding {
+                check: "redundant",
+                message: format!(
+                    "{} on the {} (id {}) is already matched by {} on the {} (id {})",
+                    domain,
+                    list_name(exact_type),
+                    id,
+                    pattern,
+                    list_name(*regex_type),
+                    regex_id
+                ),
+            });
+        }
+    }
+    Ok(findings)
+}
+
+/// Regexes of the same kind that only differ in case or surrounding whitespace, which FTL
+/// treats the same since patterns are matched case insensitively
+fn duplicate_regexes(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT type, group_concat(domain, ', '), group_concat(id, ', ') FROM domainlist
+         WHERE type IN (2, 3) GROUP BY type, lower(trim(domain)) HAVING count(*) > 1",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for row in rows {
+        let (regex_type, patterns, ids) = row?;
+        findings.push(Finding {
+            check: "duplicate",
+            message: format!(
+                "{} has the same regex more than once: {} (ids {})",
+                list_name(regex_type),
+                patterns,
+                ids
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Disabled domains and adlists which aren't assigned to any group either, so they can't be
+/// enabled for anyone without being assigned first
+fn unassigned_disabled_entries(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let mut findings: Vec<Finding> = Vec::new();
+
+    let mut stmt = conn.prepare(
+        "SELECT id, type, domain FROM domainlist d WHERE enabled = 0
+         AND NOT EXISTS (SELECT 1 FROM domainlist_by_group g WHERE g.domainlist_id = d.id)
+         ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, i32>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, domain_type, domain) = row?;
+        findings.push(Finding {
+            check: "unused",
+            message: format!(
+                "{} on the {} (id {}) is disabled and not assigned to any group",
+                domain,
+                list_name(domain_type),
+                id
+            ),
+        });
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, address FROM adlist a WHERE enabled = 0
+         AND NOT EXISTS (SELECT 1 FROM adlist_by_group g WHERE g.adlist_id = a.id)
+         ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (id, address) = row?;
+        findings.push(Finding {
+            check: "unused",
+            message: format!(
+                "adlist {} (id {}) is disabled and not assigned to any group",
+                address, id
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Groups other than Default without any clients, whose domains and adlists apply to nobody.
+/// The Default group applies to every client which isn't assigned to a group.
+fn groups_without_clients(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name,
+           (SELECT count(*) FROM domainlist_by_group d WHERE d.group_id = g.id),
+           (SELECT count(*) FROM adlist_by_group a WHERE a.group_id = g.id)
+         FROM \"group\" g WHERE id != 0
+         AND NOT EXISTS (SELECT 1 FROM client_by_group c WHERE c.group_id = g.id)
+         ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, i64>(3)?,
+        ))
+    })?;
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for row in rows {
+        let (id, name, domain_count, adlist_count) = row?;
+        findings.push(Finding {
+            check: "empty group",
+            message: format!(
+                "group {} (id {}) has no clients, its {} domains and {} adlists apply to nobody",
+                name, id, domain_count, adlist_count
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Clients, domains and adlists assigned to groups which aren't in the archive
+fn missing_groups(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let queries = [
+        (
+            "client",
+            "SELECT coalesce(c.ip, '?'), a.client_id, a.group_id FROM client_by_group a
+             LEFT JOIN client c ON c.id = a.client_id",
+        ),
+        (
+            "domain",
+            "SELECT coalesce(d.domain, '?'), a.domainlist_id, a.group_id FROM domainlist_by_group a
+             LEFT JOIN domainlist d ON d.id = a.domainlist_id",
+        ),
+        (
+            "adlist",
+            "SELECT coalesce(l.address, '?'), a.adlist_id, a.group_id FROM adlist_by_group a
+             LEFT JOIN adlist l ON l.id = a.adlist_id",
+        ),
+    ];
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for (kind, query) in queries {
+        let sql = format!(
+            "{} WHERE NOT EXISTS (SELECT 1 FROM \"group\" g WHERE g.id = a.group_id) ORDER BY 2",
+            query
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (name, id, group_id) = row?;
+            findings.push(Finding {
+                check: "missing group",
+                message: format!(
+                    "{} {} (id {}) is assigned to group id {} which doesn't exist",
+                    kind, name, id, group_id
+                ),
+            });
+        }
+    }
+    Ok(findings)
+}
+
+/// Adlists whose addresses only differ in ways that don't change what is downloaded
+fn duplicate_adlists(conn: &Connection) -> Result<Vec<Finding>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT id, address FROM adlist ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut by_url: BTreeMap<String, Vec<(i64, String)>> = BTreeMap::new();
+    for row in rows {
+        let (id, address) = row?;
+        by_url
+            .entry(normalise_url(&address))
+            .or_default()
+            .push((id, address));
+    }
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for (url, adlists) in by_url.iter().filter(|(_, adlists)| adlists.len() > 1) {
+        findings.push(Finding {
+            check: "duplicate",
+            message: format!(
+                "adlists {} are all {}",
+                adlists
+                    .iter()
+                    .map(|(id, address)| format!("{} (id {})", address, id))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                url
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Groups each domainlist entry is assigned to, by entry id
+fn domain_groups(conn: &Connection) -> Result<HashMap<i64, BTreeSet<i64>>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT domainlist_id, group_id FROM domainlist_by_group")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+
+    let mut groups: HashMap<i64, BTreeSet<i64>> = HashMap::new();
+    for row in rows {
+        let (domain_id, group_id) = row?;
+        groups.entry(domain_id).or_default().insert(group_id);
+    }
+    Ok(groups)
+}
+
+/// Lowercases the scheme and host of the given URL, and leaves out surrounding whitespace,
+/// the default port of the scheme, and a trailing slash, ex: `HTTPS://Example.com:443/hosts/`
+/// becomes `https://example.com/hosts`
+fn normalise_url(address: &str) -> String {
+    let address = address.trim();
+    let (scheme, rest) = match address.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => (String::new(), address),
+    };
+    let (host, path) = match rest.find(['/', '?', '#']) {
+        Some(end) => rest.split_at(end),
+        None => (rest, ""),
+    };
+
+    let mut host = host.to_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => Some(":80"),
+        "https" => Some(":443"),
+        _ => None,
+    };
+    if let Some(port) = default_port {
+        if let Some(stripped) = host.strip_suffix(port) {
+            host = stripped.to_string();
+        }
+    }
+
+    let path = path.trim_end_matches('/');
+    if scheme.is_empty() {
+        format!("{}{}", host, path)
+    } else {
+        format!("{}://{}{}", scheme, host, path)
+    }
+}
+
+fn list_name(domain_type: i32) -> String {
+    match DomainType::from_id(domain_type) {
+        Some(domain_type) => domain_type.to_string(),
+        None => format!("domainlist type {}", domain_type),
+    }
+}
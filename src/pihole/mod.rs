@@ -1,4 +1,23 @@
+pub mod archive;
 pub mod cli;
+pub mod conf;
+pub mod conflict;
 pub mod dhcp;
 pub mod dns;
+pub mod dnsmasq;
+pub mod domain;
+pub mod explain;
+pub mod files;
+pub mod filter;
+pub mod ftl;
 pub mod gravity;
+pub mod hosts;
+pub mod info;
+pub mod inspect;
+pub mod lint;
+pub mod regexp;
+pub mod schema;
+pub mod scope;
+pub mod setupvars;
+pub mod summary;
+pub mod wildcard;
@@ -0,0 +1,309 @@
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::raw::c_char;
+
+/// query types FTL understands in the `;querytype=` extension
+const QUERY_TYPES: [&str; 16] = [
+    "A", "AAAA", "ANY", "SRV", "SOA", "PTR", "TXT", "NAPTR", "MX", "DS", "RRSIG", "DNSKEY", "NS",
+    "OTHER", "SVCB", "HTTPS",
+];
+
+/// reply types FTL understands in the `;reply=` extension, besides IP addresses
+const REPLY_TYPES: [&str; 5] = ["NODATA", "NXDOMAIN", "REFUSED", "IP", "NONE"];
+
+/// Escapes that TRE, the regex library FTL is built with, reads as a character class, and the
+/// system's POSIX regex implementation as the escaped character
+const TRE_CLASS_ESCAPES: [char; 2] = ['d', 'D'];
+
+/// A regex list entry as FTL compiles it: a POSIX extended regular expression before the first
+/// `;`, followed by `;` separated extensions
+pub struct FtlRegex {
+    /// the compiled pattern, none if it uses TRE syntax which can't be evaluated here
+    regex: Option<PosixRegex>,
+    /// the TRE syntax the pattern uses, which the system's regex implementation rejects or
+    /// reads differently, so that the entry can't be matched the way FTL does
+    pub unsupported: Option<String>,
+    /// query types the regex applies to, all of them except these when negated
+    query_types: Option<(bool, Vec<String>)>,
+    /// whether the entry matches the domains the pattern doesn't match
+    pub invert: bool,
+    /// extensions FTL doesn't know about, which it ignores
+    pub unknown: Vec<String>,
+}
+
+impl FtlRegex {
+    pub fn parse(entry: &str) -> Result<FtlRegex, String> {
+        let mut parts = entry.split(';');
+        let pattern = parts.next().unwrap_or_default();
+        if pattern.is_empty() {
+            return Err(String::from("empty pattern"));
+        }
+
+        let mut query_types = None;
+        let mut invert = false;
+        let mut unknown: Vec<String> = Vec::new();
+        for extension in parts {
+            let (name, value) = match extension.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (extension, None),
+            };
+            match (name, value) {
+                ("querytype", Some(value)) => query_types = Some(parse_query_types(value)?),
+                ("reply", Some(value)) => validate_reply(value)?,
+                ("invert", None) => invert = true,
+                ("querytype", None) | ("reply", None) => {
+                    return Err(format!("`;{}` is missing its value", name))
+                }
+                ("invert", Some(_)) => return Err(String::from("`;invert` does not take a value")),
+                _ => unknown.push(extension.to_string()),
+            }
+        }
+
+        // only TRE can tell whether such a pattern compiles, FTL logs the ones that don't
+        let unsupported = tre_syntax(pattern);
+        let regex = match unsupported {
+            Some(_) => None,
+            None => Some(PosixRegex::new(pattern)?),
+        };
+        Ok(FtlRegex {
+            regex,
+            unsupported,
+            query_types,
+            invert,
+            unknown,
+        })
+    }
+
+    /// Whether the entry applies to queries of any type, ie. it has no `;querytype=`
+    pub fn applies_to_all_query_types(&self) -> bool {
+        self.query_types.is_none()
+    }
+
+    /// Checks whether the entry matches a query of the given type for the given domain. Entries
+    /// with `unsupported` syntax never match.
+    pub fn matches(&self, domain: &str, query_type: &str) -> bool {
+        if let Some((negated, types)) = &self.query_types {
+            let listed = types.iter().any(|t| t.eq_ignore_ascii_case(query_type));
+            if listed == *negated {
+                return false;
+            }
+        }
+        match &self.regex {
+            Some(regex) => regex.is_match(domain) != self.invert,
+            None => false,
+        }
+    }
+}
+
+/// Checks whether the given name is a query type FTL understands
+pub fn is_query_type(name: &str) -> bool {
+    QUERY_TYPES.contains(&name.to_uppercase().as_str())
+}
+
+/// Finds syntax in the given pattern that TRE reads differently from the system's POSIX regex
+/// implementation: class escapes such as `\d`, and bounds with approximate matching
+/// parameters such as `{~1}`, which glibc rejects
+fn tre_syntax(pattern: &str) -> Option<String> {
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if TRE_CLASS_ESCAPES.contains(&escaped) => {
+                    return Some(format!(
+                        "`\\{}` is a character class in FTL, not the letter {}",
+                        escaped, escaped
+                    ))
+                }
+                _ => {}
+            },
+            // backslashes and braces are literal in bracket expressions, ex: `[\d{]`
+            '[' => {
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' => break,
+                        // classes such as `[:digit:]` end with their own `:]`
+                        '[' => {
+                            if let Some(kind) = chars.next_if(|c| matches!(c, ':' | '.' | '=')) {
+                                let mut previous = kind;
+                                for c in chars.by_ref() {
+                                    if previous == kind && c == ']' {
+                                        break;
+                                    }
+                                    previous = c;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            '{' => {
+                let bound: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                if bound.contains(['~', '+', '-', '#']) {
+                    return Some(format!(
+                        "`{{{}}}` uses approximate matching, which only FTL supports",
+                        bound
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Query types are given as a comma separated list, which is negated by a leading `!`, ex:
+/// `;querytype=!A,AAAA`
+fn parse_query_types(value: &str) -> Result<(bool, Vec<String>), String> {
+    let (negated, types) = match value.strip_prefix('!') {
+        Some(types) => (true, types),
+        None => (false, value),
+    };
+    if types.is_empty() {
+        return Err(String::from("`;querytype=` is missing its value"));
+    }
+
+    let mut parsed: Vec<String> = Vec::new();
+    for query_type in types.split(',') {
+        if !is_query_type(query_type) {
+            return Err(format!("unknown query type `{}`", query_type));
+        }
+        parsed.push(query_type.to_uppercase());
+    }
+    Ok((negated, parsed))
+}
+
+/// Replies are a reply type, or an IPv4 and/or an IPv6 address separated by a comma, ex:
+/// `;reply=NXDOMAIN` or `;reply=192.168.1.1,fe80::1`
+fn validate_reply(value: &str) -> Result<(), String> {
+    if REPLY_TYPES.contains(&value.to_uppercase().as_str()) {
+        return Ok(());
+    }
+
+    let addresses: Vec<&str> = value.split(',').collect();
+    let ipv4_count = addresses
+        .iter()
+        .filter(|a| a.parse::<Ipv4Addr>().is_ok())
+        .count();
+    let ipv6_count = addresses
+        .iter()
+        .filter(|a| a.parse::<Ipv6Addr>().is_ok())
+        .count();
+    if addresses.len() > 2
+        || ipv4_count > 1
+        || ipv6_count > 1
+        || ipv4_count + ipv6_count != addresses.len()
+    {
+        return Err(format!("unknown reply `{}`", value));
+    }
+    Ok(())
+}
+
+/// A pattern compiled with the system's POSIX regex implementation, with the flags FTL uses
+struct PosixRegex {
+    compiled: libc::regex_t,
+}
+
+impl PosixRegex {
+    fn new(pattern: &str) -> Result<PosixRegex, String> {
+        let c_pattern =
+            CString::new(pattern).map_err(|_| String::from("pattern contains a NUL character"))?;
+
+        let mut compiled = MaybeUninit::<libc::regex_t>::zeroed();
+        // SAFETY: regcomp initialises `compiled`, which is only used further when compilation
+        // succeeded, and regerror writes at most `buffer.len()` bytes including the NUL
+        unsafe {
+            let code = libc::regcomp(
+                compiled.as_mut_ptr(),
+                c_pattern.as_ptr(),
+                libc::REG_EXTENDED | libc::REG_ICASE | libc::REG_NOSUB,
+            );
+            if code == 0 {
+                return Ok(PosixRegex {
+                    compiled: compiled.assume_init(),
+                });
+            }
+
+            let mut buffer = [0 as c_char; 256];
+            libc::regerror(code, compiled.as_ptr(), buffer.as_mut_ptr(), buffer.len());
+            Err(CStr::from_ptr(buffer.as_ptr())
+                .to_string_lossy()
+                .to_string())
+        }
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        let c_text = match CString::new(text) {
+            Ok(c_text) => c_text,
+            Err(_) => return false,
+        };
+        // SAFETY: the regex was compiled with REG_NOSUB, so no match offsets are written
+        unsafe { libc::regexec(&self.compiled, c_text.as_ptr(), 0, std::ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for PosixRegex {
+    fn drop(&mut self) {
+        // SAFETY: the regex was compiled by regcomp and is freed only once
+        unsafe { libc::regfree(&mut self.compiled) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix_patterns_are_matched() {
+        let regex = FtlRegex::parse("(^|\\.)ads?[0-9]{1,2}\\.example\\.com$").unwrap();
+        assert!(regex.unsupported.is_none());
+        assert!(regex.matches("x.ad12.example.com", "A"));
+        assert!(!regex.matches("bad1.example.com", "A"));
+    }
+
+    #[test]
+    fn approximate_matching_is_not_rejected() {
+        let regex = FtlRegex::parse("abc{~1}").unwrap();
+        assert!(regex.unsupported.as_ref().unwrap().contains("approximate"));
+        assert!(!regex.matches("abc", "A"));
+
+        let regex = FtlRegex::parse("^(doubleclick){+1-1#2}\\.net$;invert").unwrap();
+        assert!(regex.unsupported.is_some());
+        assert!(!regex.matches("example.com", "A"));
+    }
+
+    #[test]
+    fn class_escapes_are_not_evaluated() {
+        let regex = FtlRegex::parse("^ad\\d+\\.").unwrap();
+        assert!(regex.unsupported.as_ref().unwrap().contains("\\d"));
+        assert!(!regex.matches("add.example.com", "A"));
+
+        // glibc reads these escapes the way TRE does
+        let regex = FtlRegex::parse("^a\\sb\\w$").unwrap();
+        assert!(regex.unsupported.is_none());
+        assert!(regex.matches("a bc", "A"));
+    }
+
+    #[test]
+    fn bracket_expressions_are_literal() {
+        for pattern in ["[\\d]", "[{~]x", "[]{~]", "[[:digit:]{~]", "[^]\\D]"] {
+            let regex = FtlRegex::parse(pattern).unwrap();
+            assert!(regex.unsupported.is_none(), "{}", pattern);
+        }
+        assert!(FtlRegex::parse("[[:digit:]]\\d")
+            .unwrap()
+            .unsupported
+            .is_some());
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(FtlRegex::parse("(ads").is_err());
+        assert!(FtlRegex::parse("a{2,1}").is_err());
+        assert!(FtlRegex::parse("").is_err());
+        assert!(FtlRegex::parse("ads;querytype=A,XYZ").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use log::{debug, info, warn};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::path::Path;
+
+/// Schema of a fresh gravity database as created by Pi-hole v5
+/// https://github.com/pi-hole/pi-hole/blob/master/advanced/Templates/gravity.db.sql
+const GRAVITY_SCHEMA: &str = include_str!("gravity.sql");
+
+/// The `info.version` of the gravity database created from the embedded schema
+pub const GRAVITY_SCHEMA_VERSION: i32 = 15;
+
+/// Oldest gravity schema that has the unified domainlist and group management tables the
+/// archive sections are restored into
+pub const MIN_SUPPORTED_VERSION: i32 = 10;
+
+/// Columns that only exist from a given gravity schema version onwards. Archives taken from
+/// older releases don't have these fields, and newer releases may add fields unknown here.
+const VERSIONED_COLUMNS: &[(&str, &str, i32)] = &[
+    ("adlist", "date_updated", 14),
+    ("adlist", "number", 14),
+    ("adlist", "invalid_domains", 14),
+    ("adlist", "status", 14),
+    ("adlist", "abp_entries", 16),
+    ("adlist", "type", 17),
+];
+
+/// Creates a new gravity database at the given location, initialised with the Pi-hole v5
+/// schema including the views, triggers, and the Default group
+pub fn create_gravity_db(db_file: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(db_file).exists() {
+        return Err(Box::<dyn Error>::from(format!(
+            "not creating gravity db since the file already exists: {}",
+            db_file
+        )));
+    }
+
+    debug!("creating gravity db: {}", db_file);
+    let conn = Connection::open_with_flags(
+        db_file,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    conn.execute_batch(GRAVITY_SCHEMA)?;
+
+    info!(
+        "created gravity db {} with schema version {}",
+        db_file, GRAVITY_SCHEMA_VERSION
+    );
+    Ok(())
+}
+
+/// Creates an in-memory database with the Pi-hole v5 schema to load an archive into. The
+/// triggers linking new entries to the Default group are dropped, so that the group
+/// assignments are the ones in the archive.
+pub fn create_in_memory_db() -> Result<Connection, Box<dyn Error>> {
+    let conn = Connection::open_in_memory()?;
+    conn.execute_batch(GRAVITY_SCHEMA)?;
+    conn.execute_batch(
+        "DROP TRIGGER tr_domainlist_add; DROP TRIGGER tr_client_add; DROP TRIGGER tr_adlist_add;",
+    )?;
+    Ok(conn)
+}
+
+/// Reads the schema version from the `info` table of the given gravity database
+pub fn read_version(conn: &Connection) -> Result<Option<i32>, Box<dyn Error>> {
+    if table_columns(conn, "info")?.is_empty() {
+        return Ok(None);
+    }
+
+    let version: Option<String> = conn
+        .query_row(
+            "SELECT value FROM info WHERE property = 'version'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match version {
+        Some(v) => Ok(Some(v.trim().parse::<i32>().map_err(|e| {
+            Box::<dyn Error>::from(format!("invalid gravity schema version {}: {}", v, e))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks whether archive sections can be restored into the gravity database with the given
+/// schema version, returning the reason if they can't
+pub fn check_target_version(version: Option<i32>) -> Result<(), String> {
+    match version {
+        Some(v) if v < MIN_SUPPORTED_VERSION => Err(format!(
+            "gravity schema version {} predates the domainlist and group tables (version {}), upgrade Pi-hole first",
+            v, MIN_SUPPORTED_VERSION
+        )),
+        Some(v) => {
+            if v > GRAVITY_SCHEMA_VERSION {
+                debug!(
+                    "gravity schema version {} is newer than version {}, unknown columns will be left to their defaults",
+                    v, GRAVITY_SCHEMA_VERSION
+                );
+            }
+            Ok(())
+        }
+        None => {
+            warn!("could not determine the gravity schema version, restoring by column names");
+            Ok(())
+        }
+    }
+}
+
+/// Returns the column names of the given table, which is empty if the table doesn't exist
+pub fn table_columns(conn: &Connection, table: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?)")?;
+    let columns = stmt
+        .query_map(params![table], |row| row.get::<_, String>(0))?
+        .collect::<Result<HashSet<String>, _>>()?;
+    Ok(columns)
+}
+
+/// Whether the given table has columns that only exist from a given gravity schema version
+pub fn has_versioned_columns(table: &str) -> bool {
+    VERSIONED_COLUMNS.iter().any(|(t, _, _)| *t == table)
+}
+
+/// The oldest gravity schema version an archive section with the given fields could have
+/// been taken from, if any of its fields are version specific
+pub fn implied_version(table: &str, fields: &BTreeSet<String>) -> Option<i32> {
+    VERSIONED_COLUMNS
+        .iter()
+        .filter(|(t, column, _)| *t == table && fields.contains(*column))
+        .map(|(_, _, version)| *version)
+        .max()
+}
@@ -0,0 +1,139 @@
+use crate::pihole::archive;
+use crate::pihole::gravity;
+use log::info;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+
+/// Id of the Default group, which Pihole links every new domain, adlist and client to
+pub const DEFAULT_GROUP_ID: i32 = 0;
+
+/// How the restored domains, adlists and clients are assigned to groups
+#[derive(Debug, Default)]
+pub struct GroupOptions {
+    /// only the entries of these groups are restored, none restores every entry
+    pub scope: Option<GroupScope>,
+    /// group on the target every restored entry is assigned to, instead of the groups of the
+    /// archive
+    pub target_group_id: Option<i32>,
+    /// whether the archive's group assignments are restored, the restored entries are left in
+    /// the Default group otherwise
+    pub assignments: bool,
+}
+
+impl GroupOptions {
+    /// Whether the Default group link Pihole adds to a newly inserted entry is removed
+    pub fn unlinks_default(&self) -> bool {
+        match (self.target_group_id, &self.scope) {
+            (Some(group_id), _) => group_id != DEFAULT_GROUP_ID,
+            (None, Some(scope)) => self.assignments && !scope.includes_default(),
+            (None, None) => false,
+        }
+    }
+}
+
+/// The entries of an archive that belong to a set of groups, for restoring only those groups
+/// and what is assigned to them
+#[derive(Debug, Default)]
+pub struct GroupScope {
+    pub group_ids: HashSet<i32>,
+    pub domainlist_ids: HashSet<i32>,
+    pub adlist_ids: HashSet<i32>,
+    pub client_ids: HashSet<i32>,
+}
+
+impl GroupScope {
+    /// Reads the groups with the given names and the entries assigned to them from the group
+    /// sections of the given archive. Names of groups that aren't in the archive are an error.
+    pub fn from_archive(
+        archive_file: &str,
+        names: &[String],
+    ) -> Result<GroupScope, Box<dyn Error>> {
+        let mut groups: BTreeMap<String, i32> = BTreeMap::new();
+        let mut assignments: Vec<(String, i32, i32)> = Vec::new();
+
+        // the group sections come after the lists in the archive, so they are read up front
+        let mut archive = archive::open(archive_file)?;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+            let id_field = match name.as_str() {
+                "group.json" => "id",
+                "domainlist_by_group.json" => "domainlist_id",
+                "adlist_by_group.json" => "adlist_id",
+                "client_by_group.json" => "client_id",
+                _ => continue,
+            };
+
+            gravity::stream_records(
+                &mut entry,
+                gravity::DEFAULT_BATCH_SIZE,
+                |batch: Vec<Map<String, Value>>| {
+                    for record in &batch {
+                        let id = record.get(id_field).and_then(as_id);
+                        if name == "group.json" {
+                            if let (Some(id), Some(group)) =
+                                (id, record.get("name").and_then(Value::as_str))
+                            {
+                                groups.insert(group.to_string(), id);
+                            }
+                        } else if let (Some(id), Some(group_id)) =
+                            (id, record.get("group_id").and_then(as_id))
+                        {
+                            assignments.push((name.clone(), id, group_id));
+                        }
+                    }
+                    Ok(batch.len() as i32)
+                },
+            )?;
+        }
+
+        let mut scope = GroupScope::default();
+        let mut unknown: Vec<&str> = Vec::new();
+        for name in names {
+            match groups.get(name) {
+                Some(id) => {
+                    scope.group_ids.insert(*id);
+                }
+                None => unknown.push(name),
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(Box::<dyn Error>::from(format!(
+                "groups not found in the archive: {}, the archive has: {}",
+                unknown.join(", "),
+                groups.keys().cloned().collect::<Vec<String>>().join(", ")
+            )));
+        }
+
+        for (section, id, group_id) in assignments {
+            if !scope.group_ids.contains(&group_id) {
+                continue;
+            }
+            match section.as_str() {
+                "domainlist_by_group.json" => scope.domainlist_ids.insert(id),
+                "adlist_by_group.json" => scope.adlist_ids.insert(id),
+                _ => scope.client_ids.insert(id),
+            };
+        }
+
+        info!(
+            "restoring groups {} with {} domains, {} adlists and {} clients assigned to them",
+            names.join(", "),
+            scope.domainlist_ids.len(),
+            scope.adlist_ids.len(),
+            scope.client_ids.len()
+        );
+        Ok(scope)
+    }
+
+    /// Whether entries restored within this scope may keep the Default group link Pihole adds
+    /// to every new entry
+    pub fn includes_default(&self) -> bool {
+        self.group_ids.contains(&DEFAULT_GROUP_ID)
+    }
+}
+
+fn as_id(value: &Value) -> Option<i32> {
+    value.as_i64().and_then(|id| i32::try_from(id).ok())
+}
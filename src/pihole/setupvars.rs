@@ -0,0 +1,137 @@
+use crate::pihole::conf::{self, ConfFile, KeyPolicy};
+use crate::pihole::files;
+use crate::pihole::wildcard;
+use flate2::read::GzDecoder;
+use log::{debug, info};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+pub const SETUPVARS_FILE: &str = "/etc/pihole/setupVars.conf";
+
+/// keys taken from the archive unless specified otherwise
+pub const DEFAULT_ALLOWED_KEYS: &str = "PIHOLE_DNS_*,BLOCKING_ENABLED,DNSSEC,DNS_FQDN_REQUIRED,DNS_BOGUS_PRIV,REV_SERVER*,CONDITIONAL_FORWARDING*,QUERY_LOGGING,CACHE_SIZE,DNSMASQ_LISTENING";
+
+/// keys that identify the host itself, which are never taken from the archive unless specified
+/// otherwise
+pub const DEFAULT_DENIED_KEYS: &str =
+    "IPV4_ADDRESS,IPV6_ADDRESS,PIHOLE_INTERFACE,WEBPASSWORD,DHCP_ACTIVE";
+
+/// keys dnsmasq only picks up once Pihole writes them into dnsmasq.d/01-pihole.conf, since it
+/// doesn't read setupVars.conf itself
+const DNS_SETTINGS_KEYS: &str = "PIHOLE_DNS_*,DNSSEC,DNS_FQDN_REQUIRED,DNS_BOGUS_PRIV,REV_SERVER*,CONDITIONAL_FORWARDING*,CACHE_SIZE,DNSMASQ_LISTENING";
+
+/// Merges the keys permitted by the given policy from the archived setupVars.conf into the
+/// one of this Pihole setup. Returns the keys that changed.
+pub fn process_setupvars(
+    file: &mut tar::Entry<'_, GzDecoder<File>>,
+    policy: &KeyPolicy,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    let incoming = ConfFile::parse(&s);
+
+    let mut current = read_setupvars()?;
+    let changes = current.merge(&incoming, policy);
+
+    for (key, _) in incoming.entries() {
+        if !policy.permits(key) {
+            debug!("not restoring setupVars.conf key {} as per key policy", key);
+        }
+    }
+
+    if changes.is_empty() {
+        info!("setupVars.conf is already up to date");
+        return Ok(Vec::new());
+    }
+
+    conf::log_changes("setupVars.conf", &changes);
+    files::write_atomically(SETUPVARS_FILE, &current.render())?;
+
+    Ok(changes.into_iter().map(|change| change.key).collect())
+}
+
+/// The given changed keys which only take effect once Pihole rewrites dnsmasq.d/01-pihole.conf
+pub fn dns_settings(keys: &[String]) -> Vec<String> {
+    let patterns = wildcard::split_list(DNS_SETTINGS_KEYS);
+    keys.iter()
+        .filter(|key| wildcard::matches_any(&patterns, key))
+        .cloned()
+        .collect()
+}
+
+/// The `pihole -a -i` listening mode of this Pihole setup, which rewrites
+/// dnsmasq.d/01-pihole.conf from setupVars.conf. Pihole listens on a single interface unless
+/// `DNSMASQ_LISTENING` says otherwise.
+pub fn listening_mode() -> Result<String, Box<dyn Error>> {
+    let mode = match read_setupvars()?.get("DNSMASQ_LISTENING") {
+        Some(mode @ ("all" | "local" | "bind")) => mode.to_string(),
+        _ => String::from("single"),
+    };
+    Ok(mode)
+}
+
+/// Reads the setupVars.conf of this Pihole setup
+pub fn read_setupvars() -> Result<ConfFile, Box<dyn Error>> {
+    Ok(ConfFile::parse(&files::read_or_empty(SETUPVARS_FILE)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_policy() -> KeyPolicy {
+        KeyPolicy::from_lists(DEFAULT_ALLOWED_KEYS, DEFAULT_DENIED_KEYS)
+    }
+
+    #[test]
+    fn allowed_keys_are_merged_and_denied_ones_kept() {
+        let mut current = ConfFile::parse(
+            "PIHOLE_INTERFACE=eth0\nIPV4_ADDRESS=10.0.0.2/24\nPIHOLE_DNS_1=9.9.9.9\nDNSSEC=true\nTEMPERATUREUNIT=C\n",
+        );
+        let incoming = ConfFile::parse(
+            "PIHOLE_INTERFACE=wlan0\nIPV4_ADDRESS=192.168.1.2/24\nPIHOLE_DNS_1=1.1.1.1\nPIHOLE_DNS_2=1.0.0.1\nDNSSEC=true\nWEBPASSWORD=abc\nTEMPERATUREUNIT=F\n",
+        );
+
+        let changes = current.merge(&incoming, &default_policy());
+        let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, vec!["PIHOLE_DNS_1", "PIHOLE_DNS_2"]);
+        assert_eq!(changes[0].old.as_deref(), Some("9.9.9.9"));
+        assert_eq!(changes[1].old, None);
+
+        assert_eq!(
+            current.render(),
+            "PIHOLE_INTERFACE=eth0\nIPV4_ADDRESS=10.0.0.2/24\nPIHOLE_DNS_1=1.1.1.1\nDNSSEC=true\nTEMPERATUREUNIT=C\nPIHOLE_DNS_2=1.0.0.1\n"
+        );
+    }
+
+    #[test]
+    fn denying_wins_over_allowing() {
+        let policy = KeyPolicy::from_lists("*", "PIHOLE_DNS_2,WEB*");
+        assert!(policy.permits("PIHOLE_DNS_1"));
+        assert!(!policy.permits("PIHOLE_DNS_2"));
+        assert!(!policy.permits("WEBPASSWORD"));
+
+        let policy = default_policy();
+        assert!(policy.permits("REV_SERVER_TARGET"));
+        assert!(!policy.permits("DHCP_ACTIVE"));
+        assert!(!policy.permits("TEMPERATUREUNIT"));
+    }
+
+    #[test]
+    fn dns_settings_are_picked_from_the_changed_keys() {
+        let keys: Vec<String> = [
+            "PIHOLE_DNS_1",
+            "BLOCKING_ENABLED",
+            "REV_SERVER_CIDR",
+            "DNSSEC",
+        ]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+        assert_eq!(
+            dns_settings(&keys),
+            vec!["PIHOLE_DNS_1", "REV_SERVER_CIDR", "DNSSEC"]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use log::{info, warn};
+use std::error::Error;
+use std::time::Duration;
+
+/// Outcome of the sections processed during a restore, printed at the end of the run
+#[derive(Debug, Default)]
+pub struct Summary {
+    sections: Vec<SectionResult>,
+    notes: Vec<String>,
+}
+
+#[derive(Debug)]
+struct SectionResult {
+    name: String,
+    count: Option<i32>,
+    error: Option<String>,
+    elapsed: Duration,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary::default()
+    }
+
+    /// Records the result of processing a section and how long it took
+    pub fn add(&mut self, name: &str, result: &Result<i32, Box<dyn Error>>, elapsed: Duration) {
+        let (count, error) = match result {
+            Ok(count) => (Some(*count), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        self.sections.push(SectionResult {
+            name: name.to_string(),
+            count,
+            error,
+            elapsed,
+        });
+    }
+
+    /// Adds a note that needs the attention of the user, printed after the section results
+    pub fn note(&mut self, note: &str) {
+        self.notes.push(note.to_string());
+    }
+
+    pub fn print(&self) {
+        if self.sections.is_empty() {
+            info!("summary: no sections were processed");
+        } else {
+            let total: Duration = self.sections.iter().map(|s| s.elapsed).sum();
+            info!(
+                "summary: processed {} sections in {:.2}s",
+                self.sections.len(),
+                total.as_secs_f64()
+            );
+        }
+
+        for section in &self.sections {
+            match (&section.count, &section.error) {
+                (Some(count), _) => info!(
+                    "  {}: {} entries in {:.2}s",
+                    section.name,
+                    count,
+                    section.elapsed.as_secs_f64()
+                ),
+                (None, Some(e)) => warn!(
+                    "  {}: failed after {:.2}s: {}",
+                    section.name,
+                    section.elapsed.as_secs_f64(),
+                    e
+                ),
+                (None, None) => {}
+            }
+        }
+
+        for note in &self.notes {
+            warn!("  note: {}", note);
+        }
+    }
+}